@@ -28,6 +28,7 @@ fn main() {
         .args(["--allowlist-var", "MAX_DEBUG_.*"])
         .args(["--rustified-enum", "CRAS_.*"])
         .args(["--rustified-enum", "_snd_pcm_.*"])
+        .args(["--rustified-enum", "ionode_attr"])
         .args(["--bitfield-enum", "CRAS_STREAM_EFFECT"])
         .args(["--output", gen_file.to_str().unwrap()])
         .status()
@@ -35,10 +35,10 @@ pub mod gen {
 }
 use gen::{
     _snd_pcm_format, audio_dev_debug_info, audio_message, audio_stream_debug_info,
-    cras_audio_format_packed, cras_iodev_info, cras_ionode_info, cras_ionode_info__bindgen_ty_1,
-    cras_timespec, snd_pcm_format_t, CRAS_AUDIO_MESSAGE_ID, CRAS_CHANNEL, CRAS_CLIENT_TYPE,
-    CRAS_IODEV_LAST_OPEN_RESULT, CRAS_NODE_TYPE, CRAS_SCREEN_ROTATION, CRAS_STREAM_DIRECTION,
-    CRAS_STREAM_EFFECT, CRAS_STREAM_TYPE,
+    cras_attached_client_info, cras_audio_format_packed, cras_iodev_info, cras_ionode_info,
+    cras_ionode_info__bindgen_ty_1, cras_timespec, snd_pcm_format_t, CRAS_AUDIO_MESSAGE_ID,
+    CRAS_CHANNEL, CRAS_CLIENT_TYPE, CRAS_IODEV_LAST_OPEN_RESULT, CRAS_NODE_TYPE,
+    CRAS_SCREEN_ROTATION, CRAS_STREAM_DIRECTION, CRAS_STREAM_EFFECT, CRAS_STREAM_TYPE,
 };
 
 use audio_streams::{SampleFormat, StreamDirection, StreamEffect};
@@ -47,14 +47,27 @@ unsafe impl data_model::DataInit for gen::audio_message {}
 unsafe impl data_model::DataInit for gen::audio_debug_info {}
 unsafe impl data_model::DataInit for gen::audio_dev_debug_info {}
 unsafe impl data_model::DataInit for gen::audio_stream_debug_info {}
+unsafe impl data_model::DataInit for gen::cras_add_active_node {}
+unsafe impl data_model::DataInit for gen::cras_attached_client_info {}
+unsafe impl data_model::DataInit for gen::cras_client_active_node_changed {}
 unsafe impl data_model::DataInit for gen::cras_client_connected {}
+unsafe impl data_model::DataInit for gen::cras_client_mute_changed {}
+unsafe impl data_model::DataInit for gen::cras_client_node_value_changed {}
+unsafe impl data_model::DataInit for gen::cras_client_nodes_changed {}
+unsafe impl data_model::DataInit for gen::cras_client_num_active_streams_changed {}
 unsafe impl data_model::DataInit for gen::cras_client_stream_connected {}
+unsafe impl data_model::DataInit for gen::cras_client_volume_changed {}
 unsafe impl data_model::DataInit for gen::cras_connect_message {}
 unsafe impl data_model::DataInit for gen::cras_disconnect_stream_message {}
 unsafe impl data_model::DataInit for gen::cras_dump_audio_thread {}
+unsafe impl data_model::DataInit for gen::cras_dump_dsp_info {}
 unsafe impl data_model::DataInit for gen::cras_iodev_info {}
 unsafe impl data_model::DataInit for gen::cras_ionode_info {}
+unsafe impl data_model::DataInit for gen::cras_register_notification {}
+unsafe impl data_model::DataInit for gen::cras_rm_active_node {}
+unsafe impl data_model::DataInit for gen::cras_select_node {}
 unsafe impl data_model::DataInit for gen::cras_server_state {}
+unsafe impl data_model::DataInit for gen::cras_set_node_attr {}
 unsafe impl data_model::DataInit for gen::cras_set_system_mute {}
 unsafe impl data_model::DataInit for gen::cras_set_system_volume {}
 
@@ -67,6 +80,7 @@ pub enum Error {
     InvalidClientTypeStr,
     InvalidCrasIodevNodeId,
     InvalidScreenRotation,
+    InvalidStreamDirection(u32),
     InvalidStreamType(u32),
     InvalidStreamTypeStr,
 }
@@ -92,6 +106,11 @@ impl fmt::Display for Error {
             InvalidClientTypeStr => write!(f, "Invalid client type string"),
             InvalidCrasIodevNodeId => write!(f, "Invalid iodev_node_id string"),
             InvalidScreenRotation => write!(f, "Invalid screen rotation"),
+            InvalidStreamDirection(d) => write!(
+                f,
+                "Stream direction {} has no corresponding StreamDirection",
+                d
+            ),
             InvalidStreamType(t) => write!(
                 f,
                 "Stream type {} is not within valid range [0, {})",
@@ -186,6 +205,37 @@ impl cras_audio_format_packed {
         }
         channel_layout
     }
+
+    /// Gets the channel of each sample of a frame, in frame order.
+    ///
+    /// `channel_layout` holds the position in the frame of each channel, or -1 if
+    /// the channel is absent. A position without a channel is skipped.
+    /// ```
+    /// use cras_sys::gen::{
+    ///     _snd_pcm_format,
+    ///     cras_audio_format_packed,
+    ///     CRAS_CHANNEL::*,
+    ///     CRAS_STREAM_DIRECTION::*
+    /// };
+    /// let format = cras_audio_format_packed::new(
+    ///     _snd_pcm_format::SND_PCM_FORMAT_S16,
+    ///     48000,
+    ///     6,
+    ///     CRAS_STREAM_OUTPUT
+    /// );
+    /// assert_eq!(
+    ///     format.channels(),
+    ///     vec![CRAS_CH_FL, CRAS_CH_FR, CRAS_CH_FC, CRAS_CH_LFE, CRAS_CH_RL, CRAS_CH_RR]
+    /// );
+    /// ```
+    pub fn channels(&self) -> Vec<CRAS_CHANNEL> {
+        let channel_layout = self.channel_layout;
+        let num_channels = self.num_channels as usize;
+        (0..num_channels.min(channel_layout.len()))
+            .filter_map(|idx| channel_layout.iter().position(|&pos| pos == idx as i8))
+            .filter_map(|channel| CRAS_CHANNEL::try_from(channel as i8).ok())
+            .collect()
+    }
 }
 
 impl Default for audio_message {
@@ -198,6 +248,37 @@ impl Default for audio_message {
     }
 }
 
+impl Default for cras_attached_client_info {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        }
+    }
+}
+
+/// A client attached to the server, as listed in the server state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CrasAttachedClientInfo {
+    pub id: u32,
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl From<cras_attached_client_info> for CrasAttachedClientInfo {
+    fn from(info: cras_attached_client_info) -> Self {
+        Self {
+            id: info.id,
+            pid: info.pid,
+            uid: info.uid,
+            gid: info.gid,
+        }
+    }
+}
+
 impl Default for cras_iodev_info {
     fn default() -> Self {
         Self {
@@ -212,8 +293,14 @@ impl Default for cras_iodev_info {
 
 #[derive(Debug)]
 pub struct CrasIodevInfo {
+    /// The index of the device, which changes when the device is reconnected.
     pub index: u32,
     pub name: String,
+    /// The id of the device which does not change when the device is
+    /// reconnected or the system reboots. It is a hash of the name of the
+    /// device and the card position, or the vendor, product and serial number of
+    /// USB devices, so identical USB devices without serial number share it.
+    pub stable_id: u32,
 }
 
 fn cstring_to_string(cstring: &[c_char]) -> String {
@@ -232,6 +319,7 @@ impl From<cras_iodev_info> for CrasIodevInfo {
         Self {
             index: info.idx,
             name: cstring_to_string(&info.name),
+            stable_id: info.stable_id,
         }
     }
 }
@@ -276,14 +364,92 @@ impl From<u32> for CRAS_NODE_TYPE {
             6 => CRAS_NODE_TYPE_HOTWORD,
             7 => CRAS_NODE_TYPE_POST_MIX_PRE_DSP,
             8 => CRAS_NODE_TYPE_POST_DSP,
-            9 => CRAS_NODE_TYPE_USB,
-            10 => CRAS_NODE_TYPE_BLUETOOTH,
+            9 => CRAS_NODE_TYPE_POST_DSP_DELAYED,
+            10 => CRAS_NODE_TYPE_BLUETOOTH_NB_MIC,
+            11 => CRAS_NODE_TYPE_USB,
+            12 => CRAS_NODE_TYPE_BLUETOOTH,
+            13 => CRAS_NODE_TYPE_FALLBACK_NORMAL,
+            14 => CRAS_NODE_TYPE_FALLBACK_ABNORMAL,
+            16 => CRAS_NODE_TYPE_ECHO_REFERENCE,
+            17 => CRAS_NODE_TYPE_ALSA_LOOPBACK,
+            18 => CRAS_NODE_TYPE_FLOOP,
+            19 => CRAS_NODE_TYPE_FLOOP_INTERNAL,
             _ => CRAS_NODE_TYPE_UNKNOWN,
         }
     }
 }
 
-#[derive(PartialEq, Debug)]
+impl CRAS_NODE_TYPE {
+    /// Gets all the node types, in the order of their values.
+    /// ```
+    /// use cras_sys::gen::CRAS_NODE_TYPE;
+    /// for (value, node_type) in CRAS_NODE_TYPE::all().iter().enumerate() {
+    ///     assert_eq!(CRAS_NODE_TYPE::from(value as u32), *node_type);
+    /// }
+    /// ```
+    pub fn all() -> &'static [CRAS_NODE_TYPE] {
+        use CRAS_NODE_TYPE::*;
+        &[
+            CRAS_NODE_TYPE_INTERNAL_SPEAKER,
+            CRAS_NODE_TYPE_HEADPHONE,
+            CRAS_NODE_TYPE_HDMI,
+            CRAS_NODE_TYPE_HAPTIC,
+            CRAS_NODE_TYPE_LINEOUT,
+            CRAS_NODE_TYPE_MIC,
+            CRAS_NODE_TYPE_HOTWORD,
+            CRAS_NODE_TYPE_POST_MIX_PRE_DSP,
+            CRAS_NODE_TYPE_POST_DSP,
+            CRAS_NODE_TYPE_POST_DSP_DELAYED,
+            CRAS_NODE_TYPE_BLUETOOTH_NB_MIC,
+            CRAS_NODE_TYPE_USB,
+            CRAS_NODE_TYPE_BLUETOOTH,
+            CRAS_NODE_TYPE_FALLBACK_NORMAL,
+            CRAS_NODE_TYPE_FALLBACK_ABNORMAL,
+            CRAS_NODE_TYPE_UNKNOWN,
+            CRAS_NODE_TYPE_ECHO_REFERENCE,
+            CRAS_NODE_TYPE_ALSA_LOOPBACK,
+            CRAS_NODE_TYPE_FLOOP,
+            CRAS_NODE_TYPE_FLOOP_INTERNAL,
+        ]
+    }
+
+    /// Gets a human readable name of the node type, e.g. for a settings UI.
+    ///
+    /// Internal and external microphones share `CRAS_NODE_TYPE_MIC`; the
+    /// `type_name` of `CrasIonodeInfo` tells them apart.
+    /// ```
+    /// use cras_sys::gen::CRAS_NODE_TYPE::*;
+    /// assert_eq!(CRAS_NODE_TYPE_INTERNAL_SPEAKER.display_name(), "Internal Speaker");
+    /// assert_eq!(CRAS_NODE_TYPE_USB.display_name(), "USB");
+    /// ```
+    pub fn display_name(&self) -> &'static str {
+        use CRAS_NODE_TYPE::*;
+        match self {
+            CRAS_NODE_TYPE_INTERNAL_SPEAKER => "Internal Speaker",
+            CRAS_NODE_TYPE_HEADPHONE => "Headphone",
+            CRAS_NODE_TYPE_HDMI => "HDMI",
+            CRAS_NODE_TYPE_HAPTIC => "Haptic",
+            CRAS_NODE_TYPE_LINEOUT => "Line Out",
+            CRAS_NODE_TYPE_MIC => "Microphone",
+            CRAS_NODE_TYPE_HOTWORD => "Hotword",
+            CRAS_NODE_TYPE_POST_MIX_PRE_DSP => "Post Mix Loopback",
+            CRAS_NODE_TYPE_POST_DSP => "Post DSP Loopback",
+            CRAS_NODE_TYPE_POST_DSP_DELAYED => "Delayed Post DSP Loopback",
+            CRAS_NODE_TYPE_BLUETOOTH_NB_MIC => "Bluetooth Narrowband Microphone",
+            CRAS_NODE_TYPE_USB => "USB",
+            CRAS_NODE_TYPE_BLUETOOTH => "Bluetooth",
+            CRAS_NODE_TYPE_FALLBACK_NORMAL => "Fallback",
+            CRAS_NODE_TYPE_FALLBACK_ABNORMAL => "Fallback (Abnormal)",
+            CRAS_NODE_TYPE_UNKNOWN => "Unknown",
+            CRAS_NODE_TYPE_ECHO_REFERENCE => "Echo Reference",
+            CRAS_NODE_TYPE_ALSA_LOOPBACK => "ALSA Loopback",
+            CRAS_NODE_TYPE_FLOOP => "Flexible Loopback",
+            CRAS_NODE_TYPE_FLOOP_INTERNAL => "Flexible Loopback (Internal)",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub struct CrasIodevNodeId {
     pub iodev_index: u32,
     pub ionode_index: u32,
@@ -312,6 +478,19 @@ impl From<CrasIodevNodeId> for u64 {
     }
 }
 
+impl From<u64> for CrasIodevNodeId {
+    fn from(id: u64) -> CrasIodevNodeId {
+        CrasIodevNodeId {
+            iodev_index: (id >> 32) as u32,
+            ionode_index: id as u32,
+        }
+    }
+}
+
+// The bit of `cras_ionode_info.audio_effect` set if the node supports noise
+// cancellation, from `enum audio_effect_type`.
+const EFFECT_TYPE_NOISE_CANCELLATION: u32 = 1 << 0;
+
 #[derive(Debug)]
 pub struct CrasIonodeInfo {
     pub name: String,
@@ -325,6 +504,8 @@ pub struct CrasIonodeInfo {
     pub volume: u32,
     pub capture_gain: i32,
     pub plugged_time: cras_timespec,
+    pub noise_cancellation_supported: bool,
+    pub left_right_swapped: bool,
 }
 
 impl From<cras_ionode_info> for CrasIonodeInfo {
@@ -344,6 +525,8 @@ impl From<cras_ionode_info> for CrasIonodeInfo {
                 tv_sec: info.plugged_time.tv_sec,
                 tv_nsec: info.plugged_time.tv_usec * 1000,
             },
+            noise_cancellation_supported: info.audio_effect & EFFECT_TYPE_NOISE_CANCELLATION != 0,
+            left_right_swapped: info.left_right_swapped != 0,
         }
     }
 }
@@ -412,6 +595,7 @@ pub struct AudioDevDebugInfo {
     )]
     pub longest_wake: Duration,
     pub software_gain_scaler: f64,
+    pub dev_idx: u32,
 }
 
 impl From<audio_dev_debug_info> for AudioDevDebugInfo {
@@ -433,6 +617,7 @@ impl From<audio_dev_debug_info> for AudioDevDebugInfo {
             runtime: Duration::new(info.runtime_sec.into(), info.runtime_nsec),
             longest_wake: Duration::new(info.longest_wake_sec.into(), info.longest_wake_nsec),
             software_gain_scaler: info.software_gain_scaler,
+            dev_idx: info.dev_idx,
         }
     }
 }
@@ -735,6 +920,28 @@ impl CRAS_STREAM_EFFECT {
     pub fn empty() -> Self {
         CRAS_STREAM_EFFECT(0)
     }
+
+    /// Converts the effects back into the corresponding audio_streams StreamEffects.
+    /// Bits without a corresponding StreamEffect, like APM_NOISE_SUPRESSION, are
+    /// left out, and `StreamEffect::NoEffect` is never returned.
+    /// ```
+    /// use audio_streams::StreamEffect;
+    /// use cras_sys::gen::CRAS_STREAM_EFFECT;
+    ///
+    /// for effects in [vec![], vec![StreamEffect::EchoCancellation]] {
+    ///     let cras_effects: CRAS_STREAM_EFFECT = effects.iter().collect();
+    ///     assert_eq!(cras_effects.to_effects(), effects);
+    /// }
+    /// let cras_effects: CRAS_STREAM_EFFECT = [StreamEffect::NoEffect].iter().collect();
+    /// assert!(cras_effects.to_effects().is_empty());
+    /// ```
+    pub fn to_effects(&self) -> Vec<StreamEffect> {
+        [StreamEffect::EchoCancellation]
+            .iter()
+            .cloned()
+            .filter(|&effect| (*self & CRAS_STREAM_EFFECT::from(effect)).0 != 0)
+            .collect()
+    }
 }
 
 impl From<StreamDirection> for CRAS_STREAM_DIRECTION {
@@ -747,6 +954,31 @@ impl From<StreamDirection> for CRAS_STREAM_DIRECTION {
     }
 }
 
+impl TryFrom<CRAS_STREAM_DIRECTION> for StreamDirection {
+    type Error = Error;
+
+    /// Convert a CRAS_STREAM_DIRECTION into the corresponding audio_streams StreamDirection.
+    /// Only `CRAS_STREAM_OUTPUT` and `CRAS_STREAM_INPUT` have a corresponding StreamDirection.
+    /// ```
+    /// use std::convert::TryFrom;
+    /// use audio_streams::StreamDirection;
+    /// use cras_sys::gen::CRAS_STREAM_DIRECTION;
+    ///
+    /// for direction in [StreamDirection::Playback, StreamDirection::Capture] {
+    ///     let cras_direction = CRAS_STREAM_DIRECTION::from(direction);
+    ///     assert_eq!(StreamDirection::try_from(cras_direction).unwrap(), direction);
+    /// }
+    /// assert!(StreamDirection::try_from(CRAS_STREAM_DIRECTION::CRAS_STREAM_UNDEFINED).is_err());
+    /// ```
+    fn try_from(direction: CRAS_STREAM_DIRECTION) -> Result<Self, Self::Error> {
+        match direction {
+            CRAS_STREAM_DIRECTION::CRAS_STREAM_OUTPUT => Ok(StreamDirection::Playback),
+            CRAS_STREAM_DIRECTION::CRAS_STREAM_INPUT => Ok(StreamDirection::Capture),
+            _ => Err(Error::InvalidStreamDirection(direction as u32)),
+        }
+    }
+}
+
 impl From<StreamEffect> for CRAS_STREAM_EFFECT {
     /// Convert an audio_streams StreamEffect into the corresponding CRAS_STREAM_EFFECT.
     fn from(effect: StreamEffect) -> Self {
@@ -2,6 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::UnixStream;
 use std::time::Duration;
 
@@ -18,6 +19,8 @@ use crate::async_;
 pub struct AudioSocket {
     socket: async_::AudioSocket,
     ex: Executor,
+    // The fd of `socket`, which owns it.
+    fd: RawFd,
 }
 
 /// Audio message results which are exchanged by `CrasStream` and CRAS audio server.
@@ -86,9 +89,11 @@ impl AudioSocket {
     /// `socket` - A `UnixStream`.
     pub fn new(s: UnixStream) -> Self {
         let ex = Executor::new().expect("failed to create executor");
+        let fd = s.as_raw_fd();
         AudioSocket {
             socket: async_::AudioSocket::new(s, &ex).unwrap(),
             ex,
+            fd,
         }
     }
 
@@ -111,6 +116,60 @@ impl AudioSocket {
         self.ex.run_until(self.socket.read_audio_message())?
     }
 
+    /// Checks without blocking whether an `audio message`, or the end of the
+    /// socket, can be read.
+    ///
+    /// # Errors
+    /// Returns io::Error if polling the socket failed.
+    pub fn has_message(&self) -> io::Result<bool> {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Safe because pollfd is a valid pollfd struct and the count is 1.
+        let ret = unsafe { libc::poll(&mut pollfd, 1, 0) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(pollfd.revents != 0)
+    }
+
+    /// Blocks until an `audio message` can be read, or `wake` is readable.
+    ///
+    /// # Returns
+    /// `true` if a message, or the end of the socket, can be read. `false` if `wake`
+    /// is readable, even if a message can be read too.
+    ///
+    /// # Errors
+    /// Returns io::Error if polling the fds failed.
+    pub fn wait_readable(&self, wake: RawFd) -> io::Result<bool> {
+        let mut pollfds = [
+            libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: wake,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        loop {
+            // Safe because `pollfds` is an array of valid pollfd structs of the count.
+            let ret =
+                unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, -1) };
+            if ret >= 0 {
+                return Ok(pollfds[1].revents == 0);
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
     /// Blocks waiting for an `audio message` until `timeout` occurs. If `timeout`
     /// is None, blocks indefinitely.
     ///
@@ -259,6 +318,23 @@ mod tests {
         assert_eq!({ audio_msg.frames }, { ref_audio_msg.frames });
     }
 
+    #[test]
+    fn audio_socket_wait_readable() {
+        let (sender, receiver) = init_audio_socket_pair();
+        let (wake, wake_peer) = UnixStream::pair().unwrap();
+        assert!(!receiver.has_message().unwrap());
+        sender.data_ready(256).unwrap();
+        assert!(receiver.has_message().unwrap());
+        assert!(receiver.wait_readable(wake.as_raw_fd()).unwrap());
+
+        // A wake is reported without a message, and before a pending message.
+        drop(wake_peer);
+        assert!(!receiver.wait_readable(wake.as_raw_fd()).unwrap());
+        receiver.read_audio_message().unwrap();
+        assert!(!receiver.has_message().unwrap());
+        assert!(!receiver.wait_readable(wake.as_raw_fd()).unwrap());
+    }
+
     #[test]
     fn audio_socket_send_when_broken_pipe() {
         let sock1 = {
@@ -0,0 +1,122 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+use std::time::Duration;
+
+use libchromeos::sys::info;
+
+use crate::{CrasClient, CrasPlaybackData, CrasStream, Result, StreamParams};
+
+// The server only accepts buffers shorter than this duration for a stream.
+const MAX_BUFFER_DURATION: Duration = Duration::from_secs(10);
+
+/// A playback stream which is reconnected with a larger buffer after it underruns.
+///
+/// CRAS sizes the buffer of a stream once at connect and has no dynamic buffer
+/// adjustment, so the buffer can only grow by connecting a new stream with the
+/// same `StreamParams` and a larger `buffer_size`. The device the stream is
+/// pinned to, its effects and its initial volume are kept, and so is the mute of
+/// the stream. The frames pending in the old stream are dropped with it, so a
+/// regrow is heard as a short gap, like the underrun which caused it.
+///
+/// The buffer doubles on each regrow, up to `max_buffer_size` frames, which is
+/// capped to one frame less than 10 seconds, since the server rejects streams
+/// with a buffer of 10 seconds or longer.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use libcras::{AutoGrowPlayback, CrasClient, StreamParams};
+///
+/// # fn play(client: &mut CrasClient, params: StreamParams) -> Result<(), libcras::Error> {
+/// let mut playback = AutoGrowPlayback::new(client, params, 4800, Duration::from_millis(20))?;
+/// loop {
+///     // Fill `playback.stream().next_playback_buffer()`, then
+///     playback.check_underruns(client)?;
+/// }
+/// # }
+/// ```
+pub struct AutoGrowPlayback<'b> {
+    params: StreamParams,
+    stream: CrasStream<'b, CrasPlaybackData<'b>>,
+    max_buffer_size: usize,
+    underrun_threshold: Duration,
+}
+
+impl<'b> AutoGrowPlayback<'b> {
+    /// Connects a playback stream with `params` through `client`.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The client to connect the stream, and later its replacements.
+    /// * `params` - The parameters of the stream, with its initial buffer size.
+    /// * `max_buffer_size` - The largest buffer size in frames to grow to.
+    /// * `underrun_threshold` - The underrun duration between two checks which
+    ///   triggers a regrow.
+    ///
+    /// # Errors
+    ///
+    /// If connecting the stream failed, see `CrasClient::new_cras_playback_stream`.
+    pub fn new(
+        client: &mut CrasClient,
+        params: StreamParams,
+        max_buffer_size: usize,
+        underrun_threshold: Duration,
+    ) -> Result<Self> {
+        let stream = client.new_cras_playback_stream(&params)?;
+        let max_frames = MAX_BUFFER_DURATION.as_secs() as usize * params.frame_rate as usize;
+        let max_frames = max_frames.saturating_sub(1);
+        Ok(Self {
+            max_buffer_size: max_buffer_size.min(max_frames).max(params.buffer_size),
+            params,
+            stream,
+            underrun_threshold,
+        })
+    }
+
+    /// Gets the current stream. It changes after a regrow.
+    pub fn stream(&mut self) -> &mut CrasStream<'b, CrasPlaybackData<'b>> {
+        &mut self.stream
+    }
+
+    /// Gets the buffer size in frames of the current stream.
+    pub fn buffer_size(&self) -> usize {
+        self.params.buffer_size
+    }
+
+    /// Checks the underruns of the stream since the previous check, and reconnects
+    /// it with a doubled buffer if they exceed the underrun threshold and the
+    /// buffer can still grow.
+    ///
+    /// Call this regularly, e.g. after each committed buffer.
+    ///
+    /// # Returns
+    ///
+    /// Whether the stream was reconnected with a larger buffer.
+    ///
+    /// # Errors
+    ///
+    /// If connecting the new stream failed. The current stream is kept then.
+    pub fn check_underruns(&mut self, client: &mut CrasClient) -> Result<bool> {
+        let underruns = self.stream.underruns();
+        self.stream.reset_underrun_baseline();
+        if underruns <= self.underrun_threshold || self.params.buffer_size >= self.max_buffer_size {
+            return Ok(false);
+        }
+
+        let buffer_size = (self.params.buffer_size * 2).min(self.max_buffer_size);
+        let mut params = self.params.clone();
+        params.buffer_size = buffer_size;
+        let mut stream = client.new_cras_playback_stream(&params)?;
+        stream.set_muted(self.stream.is_muted());
+        info!(
+            "Stream underran for {:?}, growing buffer from {} to {} frames",
+            underruns, self.params.buffer_size, buffer_size
+        );
+        self.stream = stream;
+        self.params = params;
+        Ok(true)
+    }
+}
@@ -0,0 +1,278 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+use std::io::{self, Write};
+
+use audio_streams::{PlaybackBufferStream, SampleFormat};
+use libchromeos::sys::error;
+
+/// Batches writes of any size into full playback buffers of a stream, like
+/// `std::io::BufWriter` does for byte writers.
+///
+/// A playback buffer is only fetched from the stream once a full block of
+/// `buffer_size` frames is pending, so the caller is not blocked by the stream
+/// for small writes. If the stream requests a different number of frames, the
+/// buffer is filled with as many pending frames as it holds, and only the frames
+/// written are committed.
+///
+/// `flush` plays the pending frames, filling the rest of the last buffer with
+/// silence. Pending frames are flushed when `BufferedPlayback` is dropped. Bytes
+/// of an incomplete frame are kept until the frame is completed, and discarded
+/// on drop.
+///
+/// If fetching a playback buffer fails, `write` returns the count of bytes it
+/// kept before the failure, or the error if it kept none, so every byte counted
+/// as written is played exactly once. A playback buffer without capacity fails
+/// with `io::ErrorKind::WriteZero`.
+///
+/// # Example
+///
+/// ```
+/// use std::io::Write;
+///
+/// use audio_streams::{PlaybackBufferStream, SampleFormat};
+/// use libcras::BufferedPlayback;
+///
+/// fn play(stream: &mut dyn PlaybackBufferStream, chunks: &[&[u8]]) -> std::io::Result<()> {
+///     let mut playback = BufferedPlayback::new(stream, 2, SampleFormat::S16LE, 480);
+///     for chunk in chunks {
+///         playback.write_all(chunk)?;
+///     }
+///     playback.flush()
+/// }
+/// ```
+pub struct BufferedPlayback<'a> {
+    stream: &'a mut dyn PlaybackBufferStream,
+    frame_size: usize,
+    block_size: usize,
+    silence: u8,
+    pending: Vec<u8>,
+}
+
+impl<'a> BufferedPlayback<'a> {
+    /// Creates a `BufferedPlayback` writing to `stream`.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The playback stream to write to.
+    /// * `num_channels` - The count of audio channels of the stream.
+    /// * `format` - The format of the samples of the stream.
+    /// * `buffer_size` - The number of frames in a block, usually the buffer size
+    ///   the stream was created with.
+    pub fn new(
+        stream: &'a mut dyn PlaybackBufferStream,
+        num_channels: usize,
+        format: SampleFormat,
+        buffer_size: usize,
+    ) -> Self {
+        let frame_size = num_channels * format.sample_bytes();
+        let block_size = buffer_size.max(1) * frame_size;
+        Self {
+            stream,
+            frame_size,
+            block_size,
+            silence: match format {
+                SampleFormat::U8 => 0x80,
+                _ => 0,
+            },
+            pending: Vec::with_capacity(block_size),
+        }
+    }
+
+    /// Returns the number of complete frames waiting for a playback buffer.
+    pub fn pending_frames(&self) -> usize {
+        self.pending.len() / self.frame_size
+    }
+
+    /// Fetches a playback buffer, fills it with the pending frames, and with
+    /// silence after them if `pad`, and commits the frames written.
+    fn play_pending(&mut self, pad: bool) -> io::Result<()> {
+        let mut buffer = self
+            .stream
+            .next_playback_buffer()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let capacity = buffer.frame_capacity() * self.frame_size;
+        if capacity == 0 {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "playback buffer is empty"));
+        }
+        let len = capacity.min(self.pending_frames() * self.frame_size);
+        buffer.write_all(&self.pending[..len])?;
+        if pad {
+            buffer.write_all(&vec![self.silence; capacity - len])?;
+        }
+        buffer.commit();
+        self.pending.drain(..len);
+        Ok(())
+    }
+}
+
+impl<'a> Write for BufferedPlayback<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut accepted = 0;
+        loop {
+            if self.pending.len() >= self.block_size {
+                // The bytes already accepted stay pending, and are played by the
+                // next write or flush.
+                if let Err(e) = self.play_pending(false) {
+                    return if accepted > 0 { Ok(accepted) } else { Err(e) };
+                }
+            } else if accepted < buf.len() {
+                let len = (self.block_size - self.pending.len()).min(buf.len() - accepted);
+                self.pending.extend_from_slice(&buf[accepted..][..len]);
+                accepted += len;
+            } else {
+                return Ok(accepted);
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while self.pending_frames() > 0 {
+            self.play_pending(true)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Drop for BufferedPlayback<'a> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("BufferedPlayback::Drop error: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use audio_streams::{BoxError, BufferCommit, PlaybackBuffer};
+
+    #[derive(Default)]
+    struct FakeCommit {
+        committed: Vec<usize>,
+    }
+
+    impl BufferCommit for FakeCommit {
+        fn commit(&mut self, nframes: usize) {
+            self.committed.push(nframes);
+        }
+    }
+
+    struct FakeStream {
+        frames: usize,
+        frame_size: usize,
+        failures: usize,
+        buffers: Vec<Vec<u8>>,
+        commit: FakeCommit,
+    }
+
+    impl FakeStream {
+        fn new(frames: usize, frame_size: usize) -> Self {
+            Self {
+                frames,
+                frame_size,
+                failures: 0,
+                buffers: Vec::new(),
+                commit: FakeCommit::default(),
+            }
+        }
+    }
+
+    impl PlaybackBufferStream for FakeStream {
+        fn next_playback_buffer<'b, 's: 'b>(&'s mut self) -> Result<PlaybackBuffer<'b>, BoxError> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(Box::from("stream failed"));
+            }
+            self.buffers.push(vec![0xff; self.frames * self.frame_size]);
+            let buf = self.buffers.last_mut().unwrap();
+            PlaybackBuffer::new(self.frame_size, buf, &mut self.commit).map_err(Box::from)
+        }
+    }
+
+    #[test]
+    fn buffered_playback_batches_writes() {
+        let mut stream = FakeStream::new(4, 2);
+        {
+            let mut playback = BufferedPlayback::new(&mut stream, 1, SampleFormat::S16LE, 4);
+            playback.write_all(&[1; 3]).unwrap();
+            playback.write_all(&[2; 4]).unwrap();
+            assert_eq!(playback.pending_frames(), 3);
+            playback.write_all(&[3; 3]).unwrap();
+            assert_eq!(playback.pending_frames(), 1);
+        }
+        // The last frame is flushed on drop.
+        assert_eq!(
+            stream.buffers,
+            vec![vec![1, 1, 1, 2, 2, 2, 2, 3], vec![3, 3, 0, 0, 0, 0, 0, 0]]
+        );
+        assert_eq!(stream.commit.committed, vec![4, 4]);
+    }
+
+    #[test]
+    fn buffered_playback_does_not_pad_larger_buffers() {
+        let mut stream = FakeStream::new(8, 2);
+        {
+            let mut playback = BufferedPlayback::new(&mut stream, 1, SampleFormat::S16LE, 4);
+            playback.write_all(&[1; 8]).unwrap();
+            playback.write_all(&[2; 8]).unwrap();
+            assert_eq!(playback.pending_frames(), 0);
+        }
+        // Only the written frames are committed, the unwritten rest of each
+        // buffer is not played.
+        assert_eq!(stream.commit.committed, vec![4, 4]);
+        assert_eq!(&stream.buffers[0][..8], &[1; 8]);
+        assert_eq!(&stream.buffers[1][..8], &[2; 8]);
+    }
+
+    #[test]
+    fn buffered_playback_flush_keeps_incomplete_frame() {
+        let mut stream = FakeStream::new(4, 2);
+        let mut playback = BufferedPlayback::new(&mut stream, 2, SampleFormat::U8, 4);
+        playback.write_all(&[1; 5]).unwrap();
+        playback.flush().unwrap();
+        assert_eq!(playback.pending_frames(), 0);
+        assert_eq!(playback.pending.len(), 1);
+        drop(playback);
+        assert_eq!(stream.buffers, vec![vec![1, 1, 1, 1, 0x80, 0x80, 0x80, 0x80]]);
+    }
+
+    #[test]
+    fn buffered_playback_write_failure_keeps_accepted_bytes() {
+        let mut stream = FakeStream::new(2, 1);
+        stream.failures = 2;
+        {
+            let mut playback = BufferedPlayback::new(&mut stream, 1, SampleFormat::U8, 2);
+            // Only the first block is accepted before the failure.
+            assert_eq!(playback.write(&[1; 5]).unwrap(), 2);
+            assert_eq!(playback.pending_frames(), 2);
+            // Nothing is accepted while the pending block cannot be played.
+            playback
+                .write(&[2; 1])
+                .expect_err("write to a failed stream succeeded");
+            assert_eq!(playback.pending_frames(), 2);
+            // Retrying the rest plays every byte once.
+            playback.write_all(&[1; 3]).unwrap();
+        }
+        assert_eq!(stream.buffers, vec![vec![1, 1], vec![1, 1], vec![1, 0x80]]);
+        assert_eq!(stream.commit.committed, vec![2, 2, 2]);
+    }
+
+    #[test]
+    fn buffered_playback_zero_capacity_buffer() {
+        let mut stream = FakeStream::new(0, 2);
+        let mut playback = BufferedPlayback::new(&mut stream, 1, SampleFormat::S16LE, 2);
+        // The block is accepted before its buffer is fetched.
+        assert_eq!(playback.write(&[1; 4]).unwrap(), 4);
+        let err = playback
+            .write(&[1; 2])
+            .expect_err("write to an empty buffer succeeded");
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+        let err = playback
+            .flush()
+            .expect_err("flush to an empty buffer succeeded");
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+        // Drop the pending frames so that drop does not flush them.
+        playback.pending.clear();
+    }
+}
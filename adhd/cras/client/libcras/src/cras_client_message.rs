@@ -7,10 +7,14 @@ use std::{
 };
 
 use cras_sys::gen::{
-    cras_client_connected, cras_client_message, cras_client_stream_connected,
-    CRAS_CLIENT_MAX_MSG_SIZE,
+    cras_audio_format_packed, cras_client_active_node_changed, cras_client_connected,
+    cras_client_message, cras_client_mute_changed, cras_client_node_value_changed,
+    cras_client_num_active_streams_changed, cras_client_stream_connected,
+    cras_client_volume_changed, CRAS_CLIENT_MAX_MSG_SIZE,
     CRAS_CLIENT_MESSAGE_ID::{self, *},
+    CRAS_STREAM_DIRECTION,
 };
+use cras_sys::CrasIodevNodeId;
 use data_model::DataInit;
 use libchromeos::sys::unix::ScmSocket;
 
@@ -78,13 +82,149 @@ impl From<TryFromSliceError> for Error {
     }
 }
 
+/// A system state change notified by CRAS server.
+///
+/// The server only sends the notifications a client has registered for. See
+/// `CrasClient::next_system_event`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SystemEvent {
+    /// The system output volume changed to the given value.
+    OutputVolumeChanged(i32),
+    /// The system output mute state changed.
+    OutputMuteChanged {
+        muted: bool,
+        user_muted: bool,
+        mute_locked: bool,
+    },
+    /// The system capture gain changed to the given value.
+    CaptureGainChanged(i32),
+    /// The system capture mute state changed.
+    CaptureMuteChanged { muted: bool, mute_locked: bool },
+    /// Nodes were added or removed, or their attributes changed.
+    NodesChanged,
+    /// The active node of `direction` changed.
+    ActiveNodeChanged {
+        direction: CRAS_STREAM_DIRECTION,
+        node_id: CrasIodevNodeId,
+    },
+    /// The volume of an output node changed.
+    OutputNodeVolumeChanged {
+        node_id: CrasIodevNodeId,
+        volume: i32,
+    },
+    /// The left and right channels of a node were swapped or restored.
+    NodeLeftRightSwappedChanged {
+        node_id: CrasIodevNodeId,
+        swapped: bool,
+    },
+    /// The gain of an input node changed.
+    InputNodeGainChanged { node_id: CrasIodevNodeId, gain: i32 },
+    /// The number of active streams of `direction` changed.
+    NumActiveStreamsChanged {
+        direction: CRAS_STREAM_DIRECTION,
+        num_active_streams: u32,
+    },
+    /// The jack of a node was plugged or unplugged. The server has no notification
+    /// for jacks, so the client reports it after the `NodesChanged` which
+    /// changed the jack state, see `CrasClient::node_jack_plugged`.
+    JackPlugChanged {
+        node_id: CrasIodevNodeId,
+        plugged: bool,
+    },
+}
+
+impl SystemEvent {
+    /// All the message ids of system state notifications.
+    pub(crate) const MESSAGE_IDS: [CRAS_CLIENT_MESSAGE_ID; 10] = [
+        CRAS_CLIENT_OUTPUT_VOLUME_CHANGED,
+        CRAS_CLIENT_OUTPUT_MUTE_CHANGED,
+        CRAS_CLIENT_CAPTURE_GAIN_CHANGED,
+        CRAS_CLIENT_CAPTURE_MUTE_CHANGED,
+        CRAS_CLIENT_NODES_CHANGED,
+        CRAS_CLIENT_ACTIVE_NODE_CHANGED,
+        CRAS_CLIENT_OUTPUT_NODE_VOLUME_CHANGED,
+        CRAS_CLIENT_NODE_LEFT_RIGHT_SWAPPED_CHANGED,
+        CRAS_CLIENT_INPUT_NODE_GAIN_CHANGED,
+        CRAS_CLIENT_NUM_ACTIVE_STREAMS_CHANGED,
+    ];
+
+    // Converts a notification message into `SystemEvent`.
+    fn try_from_message(message: &CrasClientMessage) -> Result<SystemEvent> {
+        match message.get_id()? {
+            CRAS_CLIENT_OUTPUT_VOLUME_CHANGED => {
+                let cmsg: &cras_client_volume_changed = message.get_message()?;
+                Ok(SystemEvent::OutputVolumeChanged(cmsg.volume))
+            }
+            CRAS_CLIENT_OUTPUT_MUTE_CHANGED => {
+                let cmsg: &cras_client_mute_changed = message.get_message()?;
+                Ok(SystemEvent::OutputMuteChanged {
+                    muted: cmsg.muted != 0,
+                    user_muted: cmsg.user_muted != 0,
+                    mute_locked: cmsg.mute_locked != 0,
+                })
+            }
+            CRAS_CLIENT_CAPTURE_GAIN_CHANGED => {
+                let cmsg: &cras_client_volume_changed = message.get_message()?;
+                Ok(SystemEvent::CaptureGainChanged(cmsg.volume))
+            }
+            CRAS_CLIENT_CAPTURE_MUTE_CHANGED => {
+                let cmsg: &cras_client_mute_changed = message.get_message()?;
+                Ok(SystemEvent::CaptureMuteChanged {
+                    muted: cmsg.muted != 0,
+                    mute_locked: cmsg.mute_locked != 0,
+                })
+            }
+            CRAS_CLIENT_NODES_CHANGED => Ok(SystemEvent::NodesChanged),
+            CRAS_CLIENT_ACTIVE_NODE_CHANGED => {
+                let cmsg: &cras_client_active_node_changed = message.get_message()?;
+                Ok(SystemEvent::ActiveNodeChanged {
+                    direction: CRAS_STREAM_DIRECTION::from(cmsg.direction),
+                    node_id: CrasIodevNodeId::from(cmsg.node_id),
+                })
+            }
+            CRAS_CLIENT_OUTPUT_NODE_VOLUME_CHANGED => {
+                let cmsg: &cras_client_node_value_changed = message.get_message()?;
+                Ok(SystemEvent::OutputNodeVolumeChanged {
+                    node_id: CrasIodevNodeId::from(cmsg.node_id),
+                    volume: cmsg.value,
+                })
+            }
+            CRAS_CLIENT_NODE_LEFT_RIGHT_SWAPPED_CHANGED => {
+                let cmsg: &cras_client_node_value_changed = message.get_message()?;
+                Ok(SystemEvent::NodeLeftRightSwappedChanged {
+                    node_id: CrasIodevNodeId::from(cmsg.node_id),
+                    swapped: cmsg.value != 0,
+                })
+            }
+            CRAS_CLIENT_INPUT_NODE_GAIN_CHANGED => {
+                let cmsg: &cras_client_node_value_changed = message.get_message()?;
+                Ok(SystemEvent::InputNodeGainChanged {
+                    node_id: CrasIodevNodeId::from(cmsg.node_id),
+                    gain: cmsg.value,
+                })
+            }
+            CRAS_CLIENT_NUM_ACTIVE_STREAMS_CHANGED => {
+                let cmsg: &cras_client_num_active_streams_changed = message.get_message()?;
+                Ok(SystemEvent::NumActiveStreamsChanged {
+                    direction: CRAS_STREAM_DIRECTION::from(cmsg.direction),
+                    num_active_streams: cmsg.num_active_streams,
+                })
+            }
+            _ => Err(Error::MessageTypeError),
+        }
+    }
+}
+
 /// A handled server result from one message sent from CRAS server.
 pub enum ServerResult {
     /// client_id, CrasServerStateShmFd
     Connected(u32, CrasServerStateShmFd),
-    /// stream_id, header_fd, samples_fd
-    StreamConnected(u32, CrasAudioShmHeaderFd, CrasShmFd),
+    /// stream_id, header_fd, samples_fd, the format the server connected the stream with
+    StreamConnected(u32, CrasAudioShmHeaderFd, CrasShmFd, cras_audio_format_packed),
+    /// stream_id, the negative errno the server failed to connect the stream with
+    StreamConnectFailed(u32, i32),
     DebugInfoReady,
+    SystemEvent(SystemEvent),
 }
 
 impl ServerResult {
@@ -105,6 +245,13 @@ impl ServerResult {
             }
             CRAS_CLIENT_MESSAGE_ID::CRAS_CLIENT_STREAM_CONNECTED => {
                 let cmsg: &cras_client_stream_connected = message.get_message()?;
+                // A failed stream is replied without shared memory areas.
+                if cmsg.err != 0 {
+                    return Ok(ServerResult::StreamConnectFailed(cmsg.stream_id, cmsg.err));
+                }
+                if message.fds.contains(&-1) {
+                    return Err(Error::MessageNumFdError);
+                }
                 // CRAS should return two shared memory areas the first which has
                 // mem::size_of::<cras_audio_shm_header>() bytes, and the second which has
                 // `samples_shm_size` bytes.
@@ -114,11 +261,15 @@ impl ServerResult {
                     unsafe { CrasAudioShmHeaderFd::new(message.fds[0]) },
                     // Safe because CRAS ensures that the second fd has length 'samples_shm_size'
                     unsafe { CrasShmFd::new(message.fds[1], cmsg.samples_shm_size as usize) },
+                    cmsg.format,
                 ))
             }
             CRAS_CLIENT_MESSAGE_ID::CRAS_CLIENT_AUDIO_DEBUG_INFO_READY => {
                 Ok(ServerResult::DebugInfoReady)
             }
+            id if SystemEvent::MESSAGE_IDS.contains(&id) => Ok(ServerResult::SystemEvent(
+                SystemEvent::try_from_message(&message)?,
+            )),
             _ => Err(Error::MessageTypeError),
         }
     }
@@ -170,14 +321,18 @@ impl CrasClientMessage {
             CRAS_CLIENT_STREAM_CONNECTED => match fd_nums {
                 // CRAS should return two shared memory areas the first which has
                 // mem::size_of::<cras_audio_shm_header>() bytes, and the second which has
-                // `samples_shm_size` bytes.
-                2 => Ok(()),
+                // `samples_shm_size` bytes, or none if connecting the stream failed.
+                0 | 2 => Ok(()),
                 _ => Err(Error::MessageNumFdError),
             },
             CRAS_CLIENT_AUDIO_DEBUG_INFO_READY => match fd_nums {
                 0 => Ok(()),
                 _ => Err(Error::MessageNumFdError),
             },
+            id if SystemEvent::MESSAGE_IDS.contains(&id) => match fd_nums {
+                0 => Ok(()),
+                _ => Err(Error::MessageNumFdError),
+            },
             _ => Err(Error::MessageTypeError),
         }
     }
@@ -191,7 +346,11 @@ impl CrasClientMessage {
             id if id == (CRAS_CLIENT_AUDIO_DEBUG_INFO_READY as u32) => {
                 Ok(CRAS_CLIENT_AUDIO_DEBUG_INFO_READY)
             }
-            _ => Err(Error::MessageIdError),
+            id => SystemEvent::MESSAGE_IDS
+                .iter()
+                .find(|&&msg_id| msg_id as u32 == id)
+                .copied()
+                .ok_or(Error::MessageIdError),
         }
     }
 
@@ -0,0 +1,128 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+use audio_streams::AudioStreamsExecutor;
+use cras_sys::{CrasIodevInfo, CrasIodevNodeId, CrasIonodeInfo};
+
+use crate::cras_client_message::SystemEvent;
+use crate::cras_server_socket::CrasSocketType;
+use crate::cras_shm::{Topology, VolumeState};
+use crate::{CrasClient, Result};
+
+/// A read-only view of the CRAS server state.
+///
+/// `CrasObserver` connects like `CrasClient` and maps the same server state, but
+/// only exposes the getters of the system state and the system state change
+/// notifications. It cannot create streams or change any setting.
+///
+/// The server has no read-only connection, so the observer is still attached as
+/// a client and needs the same access to the server socket. It only narrows what
+/// the monitoring code can do with the connection.
+pub struct CrasObserver<'a> {
+    client: CrasClient<'a>,
+}
+
+impl<'a> CrasObserver<'a> {
+    /// Connects a `CrasObserver` to the `CrasSocketType::Legacy` socket.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if error occurs while handling server message or message
+    /// type is incorrect
+    pub fn new() -> Result<Self> {
+        Self::with_type(CrasSocketType::Legacy)
+    }
+
+    /// Connects a `CrasObserver` to the socket of `socket_type`.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if error occurs while handling server message or message
+    /// type is incorrect
+    pub fn with_type(socket_type: CrasSocketType) -> Result<Self> {
+        Ok(Self {
+            client: CrasClient::with_type(socket_type)?,
+        })
+    }
+
+    /// Gets the system volume, see `CrasClient::get_system_volume`.
+    pub fn get_system_volume(&self) -> u32 {
+        self.client.get_system_volume()
+    }
+
+    /// Gets the system mute, see `CrasClient::get_system_mute`.
+    pub fn get_system_mute(&self) -> bool {
+        self.client.get_system_mute()
+    }
+
+    /// Gets the system volume state, see `CrasClient::system_volume_state`.
+    pub fn system_volume_state(&self) -> VolumeState {
+        self.client.system_volume_state()
+    }
+
+    /// Gets the system capture mute in effect, see
+    /// `CrasClient::effective_capture_mute`.
+    pub fn effective_capture_mute(&self) -> bool {
+        self.client.effective_capture_mute()
+    }
+
+    /// Gets whether the system capture mute is locked.
+    pub fn capture_mute_locked(&self) -> bool {
+        self.client.capture_mute_locked()
+    }
+
+    /// Checks whether the server is still connected, see `CrasClient::is_alive`.
+    pub fn is_alive(&self) -> bool {
+        self.client.is_alive()
+    }
+
+    /// Gets a list of output devices.
+    pub fn output_devices(&self) -> impl Iterator<Item = CrasIodevInfo> {
+        self.client.output_devices()
+    }
+
+    /// Gets a list of input devices.
+    pub fn input_devices(&self) -> impl Iterator<Item = CrasIodevInfo> {
+        self.client.input_devices()
+    }
+
+    /// Gets a list of output nodes.
+    pub fn output_nodes(&self) -> impl Iterator<Item = CrasIonodeInfo> {
+        self.client.output_nodes()
+    }
+
+    /// Gets a list of input nodes.
+    pub fn input_nodes(&self) -> impl Iterator<Item = CrasIonodeInfo> {
+        self.client.input_nodes()
+    }
+
+    /// Gets whether the jack of the node `node_id` is plugged, see
+    /// `CrasClient::node_jack_plugged`.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::NodeNotFound` - If no node has `node_id`.
+    pub fn node_jack_plugged(&self, node_id: CrasIodevNodeId) -> Result<Option<bool>> {
+        self.client.node_jack_plugged(node_id)
+    }
+
+    /// Gets the devices and nodes in one consistent read, see
+    /// `CrasClient::topology_snapshot`.
+    pub fn topology_snapshot(&self) -> Topology {
+        self.client.topology_snapshot()
+    }
+
+    /// Waits for the next system state change, see
+    /// `CrasClient::next_system_event`.
+    ///
+    /// # Errors
+    ///
+    /// * If sending the registration messages to the server failed.
+    /// * If a message other than a notification is received.
+    pub async fn next_system_event(
+        &mut self,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<SystemEvent> {
+        self.client.next_system_event(ex).await
+    }
+}
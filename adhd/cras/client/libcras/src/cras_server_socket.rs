@@ -1,12 +1,16 @@
 // Copyright 2019 The ChromiumOS Authors
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
 use std::str::FromStr;
-use std::{io, mem};
+use std::{io, mem, ptr};
 
 use super::Error;
-use cras_sys::gen::{cras_disconnect_stream_message, cras_server_message, CRAS_SERVER_MESSAGE_ID};
+use cras_sys::gen::{
+    cras_add_active_node, cras_disconnect_stream_message, cras_rm_active_node, cras_select_node,
+    cras_server_message, cras_set_system_mute, cras_set_system_volume, CRAS_SERVER_MESSAGE_ID,
+    CRAS_STREAM_DIRECTION,
+};
 use libchromeos::sys::unix::{net::UnixSeqpacket, ScmSocket};
 use serde::{Deserialize, Serialize};
 
@@ -23,7 +27,8 @@ pub enum CrasSocketType {
 }
 
 impl CrasSocketType {
-    fn sock_path(&self) -> &str {
+    /// Returns the filesystem path of the server socket of this type.
+    pub fn sock_path(&self) -> &str {
         match self {
             Self::Legacy => "/run/cras/.cras_socket",
             Self::Unified => "/run/cras/.cras_unified",
@@ -63,6 +68,42 @@ impl CrasServerSocket {
         })
     }
 
+    /// Creates a `CrasServerSocket` from the fd of a socket which is already
+    /// connected to the server socket.
+    ///
+    /// # Errors
+    ///
+    /// * If `fd` is not a `SOCK_SEQPACKET` socket, with the kind
+    ///   `io::ErrorKind::InvalidInput`. Server messages are read by message
+    ///   boundaries, which a stream socket does not keep.
+    pub fn from_fd(fd: OwnedFd) -> io::Result<CrasServerSocket> {
+        let mut sock_type: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // Safe because `sock_type` and `len` are valid for writes and `len` is the
+        // size of `sock_type`.
+        let ret = unsafe {
+            libc::getsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_TYPE,
+                &mut sock_type as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if sock_type != libc::SOCK_SEQPACKET {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "server socket is not a SOCK_SEQPACKET socket",
+            ));
+        }
+        // Safe because `into_raw_fd` transfers the ownership of a valid socket fd.
+        let socket = unsafe { UnixSeqpacket::from_raw_fd(fd.into_raw_fd()) };
+        Ok(CrasServerSocket { socket })
+    }
+
     /// Sends a sized and packed server messge to the server socket. The message
     /// must implement `Sized` and `DataInit`.
     /// # Arguments
@@ -73,22 +114,78 @@ impl CrasServerSocket {
     /// * Length of written bytes in `usize`.
     ///
     /// # Errors
-    /// Return error if the socket fails to write message to server.
+    /// Return error if the socket fails to write message to server. If the server
+    /// closed the socket, the error is of kind `io::ErrorKind::BrokenPipe` and no
+    /// `SIGPIPE` is raised.
     pub fn send_server_message_with_fds<M: Sized + DataInit>(
         &self,
         message: &M,
         fds: &[RawFd],
     ) -> io::Result<usize> {
-        match fds.len() {
-            0 => self.socket.send(message.as_slice()),
-            _ => {
-                let ioslice = io::IoSlice::new(message.as_slice());
-                match self.send_with_fds(&[ioslice], fds) {
-                    Ok(len) => Ok(len),
-                    Err(err) => Err(io::Error::new(io::ErrorKind::Other, format!("{}", err))),
-                }
+        self.send_nosignal(message.as_slice(), fds)
+    }
+
+    // Sends `buf` with `fds` in one message. `MSG_NOSIGNAL` is set, so a closed
+    // peer fails the send with EPIPE instead of killing the process by SIGPIPE.
+    fn send_nosignal(&self, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let fds_len = mem::size_of_val(fds) as u32;
+        // Safe because CMSG_SPACE only computes a size. The buffer is made of u64
+        // to be aligned for cmsghdr.
+        let cmsg_space = unsafe { libc::CMSG_SPACE(fds_len) } as usize;
+        let mut cmsg_buf = vec![0u64; (cmsg_space + 7) / 8];
+
+        // Safe because msghdr is a plain C struct, for which zero is a valid value.
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        if !fds.is_empty() {
+            msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = cmsg_space as _;
+            // Safe because msg_control points to a buffer of CMSG_SPACE bytes for
+            // `fds`, so the first header and its data are within the buffer.
+            unsafe {
+                let cmsg = &mut *libc::CMSG_FIRSTHDR(&msg);
+                cmsg.cmsg_level = libc::SOL_SOCKET;
+                cmsg.cmsg_type = libc::SCM_RIGHTS;
+                cmsg.cmsg_len = libc::CMSG_LEN(fds_len) as _;
+                ptr::copy_nonoverlapping(
+                    fds.as_ptr(),
+                    libc::CMSG_DATA(cmsg) as *mut RawFd,
+                    fds.len(),
+                );
             }
         }
+
+        // Safe because msg and the buffers it points to are valid during the call.
+        let ret = unsafe { libc::sendmsg(self.socket.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret as usize)
+        }
+    }
+
+    /// Checks whether the peer of the socket is still connected.
+    ///
+    /// Polls the socket without waiting and without reading, so no pending
+    /// message is consumed. Returns `false` if the peer closed the socket or an
+    /// error is pending on it.
+    pub fn is_connected(&self) -> bool {
+        let mut pollfd = libc::pollfd {
+            fd: self.socket.as_raw_fd(),
+            events: libc::POLLIN | libc::POLLRDHUP,
+            revents: 0,
+        };
+        // Safe because pollfd is a valid pollfd struct and the count is 1.
+        let ret = unsafe { libc::poll(&mut pollfd, 1, 0) };
+        if ret < 0 {
+            return false;
+        }
+        pollfd.revents & (libc::POLLHUP | libc::POLLERR | libc::POLLRDHUP | libc::POLLNVAL) == 0
     }
 
     /// Creates a clone of the underlying socket. The returned clone can also be
@@ -123,6 +220,108 @@ impl CrasServerSocket {
         self.send_server_message_with_fds(&server_cmsg, &[])
             .map(|_| ())
     }
+
+    /// Send a message to request setting the system volume to `volume`.
+    ///
+    /// No response is expected.
+    ///
+    /// # Errors
+    ///
+    /// * If the message was not written to the server socket successfully.
+    pub fn set_system_volume(&self, volume: u32) -> io::Result<()> {
+        let header = cras_server_message {
+            length: mem::size_of::<cras_set_system_volume>() as u32,
+            id: CRAS_SERVER_MESSAGE_ID::CRAS_SERVER_SET_SYSTEM_VOLUME,
+        };
+        let msg = cras_set_system_volume { header, volume };
+        self.send_server_message_with_fds(&msg, &[]).map(|_| ())
+    }
+
+    /// Send a message to request setting the system mute to `mute`.
+    ///
+    /// No response is expected.
+    ///
+    /// # Errors
+    ///
+    /// * If the message was not written to the server socket successfully.
+    pub fn set_system_mute(&self, mute: bool) -> io::Result<()> {
+        let header = cras_server_message {
+            length: mem::size_of::<cras_set_system_mute>() as u32,
+            id: CRAS_SERVER_MESSAGE_ID::CRAS_SERVER_SET_SYSTEM_MUTE,
+        };
+        let msg = cras_set_system_mute {
+            header,
+            mute: mute as i32,
+        };
+        self.send_server_message_with_fds(&msg, &[]).map(|_| ())
+    }
+
+    /// Send a message to request selecting the node `node_id` as the only active
+    /// node of `direction`.
+    ///
+    /// No response is expected.
+    ///
+    /// # Errors
+    ///
+    /// * If the message was not written to the server socket successfully.
+    pub fn select_node(&self, direction: CRAS_STREAM_DIRECTION, node_id: u64) -> io::Result<()> {
+        let header = cras_server_message {
+            length: mem::size_of::<cras_select_node>() as u32,
+            id: CRAS_SERVER_MESSAGE_ID::CRAS_SERVER_SELECT_NODE,
+        };
+        let msg = cras_select_node {
+            header,
+            direction,
+            node_id,
+        };
+        self.send_server_message_with_fds(&msg, &[]).map(|_| ())
+    }
+
+    /// Send a message to request adding the node `node_id` to the active nodes of
+    /// `direction`.
+    ///
+    /// No response is expected.
+    ///
+    /// # Errors
+    ///
+    /// * If the message was not written to the server socket successfully.
+    pub fn add_active_node(
+        &self,
+        direction: CRAS_STREAM_DIRECTION,
+        node_id: u64,
+    ) -> io::Result<()> {
+        let header = cras_server_message {
+            length: mem::size_of::<cras_add_active_node>() as u32,
+            id: CRAS_SERVER_MESSAGE_ID::CRAS_SERVER_ADD_ACTIVE_NODE,
+        };
+        let msg = cras_add_active_node {
+            header,
+            direction,
+            node_id,
+        };
+        self.send_server_message_with_fds(&msg, &[]).map(|_| ())
+    }
+
+    /// Send a message to request removing the node `node_id` from the active nodes
+    /// of `direction`.
+    ///
+    /// No response is expected.
+    ///
+    /// # Errors
+    ///
+    /// * If the message was not written to the server socket successfully.
+    pub fn rm_active_node(&self, direction: CRAS_STREAM_DIRECTION, node_id: u64) -> io::Result<()> {
+        let header = cras_server_message {
+            length: mem::size_of::<cras_rm_active_node>() as u32,
+            id: CRAS_SERVER_MESSAGE_ID::CRAS_SERVER_RM_ACTIVE_NODE,
+        };
+        let msg = cras_rm_active_node {
+            header,
+            direction,
+            node_id,
+        };
+        self.send_server_message_with_fds(&msg, &[]).map(|_| ())
+    }
 }
 
 // For using `recv_with_fds` and `send_with_fds`.
@@ -138,3 +337,101 @@ impl AsRawFd for CrasServerSocket {
         self.socket.as_raw_fd()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+    use cras_sys::gen::cras_dump_audio_thread;
+
+    // Returns a connected `SOCK_SEQPACKET` socket pair, the end for the server
+    // socket as a fd and the peer end as a `UnixStream` to read it.
+    fn seqpacket_pair() -> (OwnedFd, UnixStream) {
+        let mut fds = [0; 2];
+        // Safe because `fds` is valid for writes of two fds.
+        let ret =
+            unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr()) };
+        assert_eq!(ret, 0, "socketpair failed: {}", io::Error::last_os_error());
+        // Safe because `socketpair` returned two fds which are owned by nothing else.
+        unsafe { (OwnedFd::from_raw_fd(fds[0]), UnixStream::from_raw_fd(fds[1])) }
+    }
+
+    #[test]
+    fn server_socket_from_stream_socket() {
+        let (sock1, _sock2) = UnixStream::pair().unwrap();
+        let err = CrasServerSocket::from_fd(OwnedFd::from(sock1))
+            .err()
+            .expect("a stream socket was accepted");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn server_socket_is_connected() {
+        let (sock1, sock2) = seqpacket_pair();
+        let socket = CrasServerSocket::from_fd(sock1).unwrap();
+        assert!(socket.is_connected());
+        drop(sock2);
+        assert!(!socket.is_connected());
+    }
+
+    #[test]
+    fn send_to_closed_server_socket() {
+        let socket = {
+            let (sock1, _) = seqpacket_pair();
+            CrasServerSocket::from_fd(sock1).unwrap()
+        };
+        let header = cras_server_message {
+            length: mem::size_of::<cras_dump_audio_thread>() as u32,
+            id: CRAS_SERVER_MESSAGE_ID::CRAS_SERVER_DUMP_AUDIO_THREAD,
+        };
+        let msg = cras_dump_audio_thread { header };
+        let err = socket
+            .send_server_message_with_fds(&msg, &[])
+            .expect_err("send to a closed socket succeeded");
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn select_node_message() {
+        let (sock1, mut sock2) = seqpacket_pair();
+        let socket = CrasServerSocket::from_fd(sock1).unwrap();
+        socket
+            .select_node(CRAS_STREAM_DIRECTION::CRAS_STREAM_OUTPUT, (3 << 32) | 1)
+            .expect("failed to send select node message");
+
+        let mut buf = [0u8; mem::size_of::<cras_select_node>()];
+        sock2.read_exact(&mut buf).unwrap();
+        let expected = cras_select_node {
+            header: cras_server_message {
+                length: mem::size_of::<cras_select_node>() as u32,
+                id: CRAS_SERVER_MESSAGE_ID::CRAS_SERVER_SELECT_NODE,
+            },
+            direction: CRAS_STREAM_DIRECTION::CRAS_STREAM_OUTPUT,
+            node_id: (3 << 32) | 1,
+        };
+        assert_eq!(&buf[..], expected.as_slice());
+    }
+
+    #[test]
+    fn add_active_node_message() {
+        let (sock1, mut sock2) = seqpacket_pair();
+        let socket = CrasServerSocket::from_fd(sock1).unwrap();
+        socket
+            .add_active_node(CRAS_STREAM_DIRECTION::CRAS_STREAM_INPUT, (2 << 32) | 4)
+            .expect("failed to send add active node message");
+
+        let mut buf = [0u8; mem::size_of::<cras_add_active_node>()];
+        sock2.read_exact(&mut buf).unwrap();
+        let expected = cras_add_active_node {
+            header: cras_server_message {
+                length: mem::size_of::<cras_add_active_node>() as u32,
+                id: CRAS_SERVER_MESSAGE_ID::CRAS_SERVER_ADD_ACTIVE_NODE,
+            },
+            direction: CRAS_STREAM_DIRECTION::CRAS_STREAM_INPUT,
+            node_id: (2 << 32) | 4,
+        };
+        assert_eq!(&buf[..], expected.as_slice());
+    }
+}
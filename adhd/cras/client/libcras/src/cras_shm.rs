@@ -13,12 +13,14 @@ use std::thread;
 use std::time::Duration;
 
 use cras_sys::gen::{
-    audio_dev_debug_info, audio_stream_debug_info, cras_audio_shm_header, cras_iodev_info,
-    cras_ionode_info, cras_server_state, CRAS_MAX_IODEVS, CRAS_MAX_IONODES, CRAS_NUM_SHM_BUFFERS,
+    audio_dev_debug_info, audio_stream_debug_info, cras_attached_client_info,
+    cras_audio_shm_header, cras_iodev_info, cras_ionode_info, cras_server_state,
+    CRAS_MAX_ATTACHED_CLIENTS, CRAS_MAX_IODEVS, CRAS_MAX_IONODES, CRAS_NUM_SHM_BUFFERS,
     CRAS_SERVER_STATE_VERSION, CRAS_SHM_BUFFERS_MASK, MAX_DEBUG_DEVS, MAX_DEBUG_STREAMS,
 };
 use cras_sys::{
-    AudioDebugInfo, AudioDevDebugInfo, AudioStreamDebugInfo, CrasIodevInfo, CrasIonodeInfo,
+    AudioDebugInfo, AudioDevDebugInfo, AudioStreamDebugInfo, CrasAttachedClientInfo,
+    CrasIodevInfo, CrasIodevNodeId, CrasIonodeInfo,
 };
 use data_model::{VolatileRef, VolatileSlice};
 use libchromeos::sys::warn;
@@ -66,6 +68,11 @@ pub struct CrasAudioHeader<'a> {
     buffer_offset: [VolatileRef<'a, u64>; CRAS_NUM_SHM_BUFFERS as usize],
     ts_sec: VolatileRef<'a, i64>,
     ts_nsec: VolatileRef<'a, i64>,
+    num_overruns: VolatileRef<'a, u32>,
+    underrun_duration_sec: VolatileRef<'a, i64>,
+    underrun_duration_nsec: VolatileRef<'a, i64>,
+    volume_scaler: VolatileRef<'a, f32>,
+    mute: VolatileRef<'a, i32>,
 }
 
 // It is safe to send audio buffers between threads as this struct has exclusive ownership of the
@@ -138,6 +145,11 @@ impl<'a> CrasAudioHeader<'a> {
                 ],
                 ts_sec: vref_from_addr!(addr, ts.tv_sec),
                 ts_nsec: vref_from_addr!(addr, ts.tv_nsec),
+                num_overruns: vref_from_addr!(addr, num_overruns),
+                underrun_duration_sec: vref_from_addr!(addr, underrun_duration.tv_sec),
+                underrun_duration_nsec: vref_from_addr!(addr, underrun_duration.tv_nsec),
+                volume_scaler: vref_from_addr!(addr, volume_scaler),
+                mute: vref_from_addr!(addr, mute),
             })
         }
     }
@@ -392,6 +404,16 @@ impl<'a> CrasAudioHeader<'a> {
         Ok(())
     }
 
+    /// Sets the location of the current write buffer within the samples area to
+    /// `offset`, like `set_buffer_offset` does for a given buffer.
+    ///
+    /// # Errors
+    /// If the offset is invalid, see `set_buffer_offset`.
+    pub fn set_write_buffer_offset(&mut self, offset: usize) -> io::Result<()> {
+        let idx = self.get_write_buf_idx() as usize;
+        self.set_buffer_offset(idx, offset)
+    }
+
     /// Commits written frames by switching the current buffer to the other one
     /// after samples are ready and indexes of current buffer are all set.
     /// - Sets `write_offset` of current buffer to `frame_count * frame_size`
@@ -475,6 +497,75 @@ impl<'a> CrasAudioHeader<'a> {
         Ok(())
     }
 
+    /// Whether the reader has consumed any frames since the header was created.
+    ///
+    /// The reader advances `read_offset` of the read buffer and switches to the
+    /// other buffer once it is fully read, so either is a sign of progress.
+    pub fn has_read_frames(&self) -> bool {
+        let idx = self.get_read_buf_idx() as usize;
+        idx != 0 || self.read_offset[idx].load() != 0
+    }
+
+    /// Gets the number of times captured data was overwritten because too much
+    /// accumulated before a read. The value is cumulative and is always 0 for
+    /// playback.
+    pub fn get_num_overruns(&self) -> u32 {
+        self.num_overruns.load()
+    }
+
+    /// Gets the duration of zero frames filled by the server because the stream
+    /// buffer was not filled in time. The value is cumulative and is always 0
+    /// for capture.
+    pub fn get_underrun_duration(&self) -> Duration {
+        Duration::new(
+            self.underrun_duration_sec.load() as u64,
+            self.underrun_duration_nsec.load() as u32,
+        )
+    }
+
+    /// Gets the volume scaler the server applies to the samples of the stream.
+    pub fn get_volume_scaler(&self) -> f32 {
+        self.volume_scaler.load()
+    }
+
+    /// Sets the volume scaler the server applies to the samples of the stream,
+    /// clamped to 0.0..=1.0.
+    pub fn set_volume_scaler(&mut self, scaler: f32) {
+        self.volume_scaler.store(scaler.max(0.0).min(1.0));
+    }
+
+    /// Gets whether the server mutes the stream.
+    pub fn get_mute(&self) -> bool {
+        self.mute.load() != 0
+    }
+
+    /// Sets whether the server mutes the stream.
+    pub fn set_mute(&mut self, mute: bool) {
+        self.mute.store(mute as i32);
+    }
+
+    /// Discards the readable frames in all buffers by committing them as read.
+    ///
+    /// # Returns
+    ///
+    /// * `usize` - number of discarded frames.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if index out of range.
+    pub fn discard_readable_frames(&mut self) -> io::Result<usize> {
+        let mut discarded = 0;
+        for _ in 0..CRAS_NUM_SHM_BUFFERS {
+            let frames = self.get_readable_frames()?;
+            if frames == 0 {
+                break;
+            }
+            self.commit_read_frames(frames as u32)?;
+            discarded += frames;
+        }
+        Ok(discarded)
+    }
+
     /// Returns the timestamp the first byte of buffer will be played or was recorded.
     /// - For capture, it returns the time of the next sample at read_index was recorded.
     /// - For playback, it returns the time that the next sample written will be played.
@@ -545,13 +636,122 @@ macro_rules! vslice_from_addr {
     }};
 }
 
+// Clamps a `count` of array entries read from the server state to the `capacity`
+// of the array. The count is written by the server and is not trusted.
+fn clamp_count(name: &str, count: u32, capacity: u32) -> usize {
+    if count > capacity {
+        warn!(
+            "Server state {} {} exceeds the capacity {}, clamped",
+            name, count, capacity
+        );
+        capacity as usize
+    } else {
+        count as usize
+    }
+}
+
+/// A snapshot of the system output volume.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VolumeState {
+    /// The volume index from 0 to 100.
+    pub scalar: u32,
+    /// An approximation of the volume in dBFS corresponding to `scalar`,
+    /// `f32::NEG_INFINITY` if `scalar` is 0.
+    ///
+    /// It is interpolated linearly between the dBFS the server reports for
+    /// volume 1 and volume 100. Boards with an explicit volume curve may map the
+    /// volumes in between differently.
+    pub db: f32,
+    /// Whether the system output is muted.
+    pub muted: bool,
+}
+
+impl VolumeState {
+    // The server reports the volume in dBFS * 100 of volume 1 and volume 100
+    // only, so the volumes in between are interpolated as on the default
+    // simple step curve, and only approximate boards with other curves.
+    fn scalar_to_db(scalar: u32, min_volume_dbfs: i32, max_volume_dbfs: i32) -> f32 {
+        if scalar == 0 {
+            return f32::NEG_INFINITY;
+        }
+        let scalar = scalar.min(100) as f32;
+        let range = (max_volume_dbfs - min_volume_dbfs) as f32;
+        (min_volume_dbfs as f32 + range * (scalar - 1.0) / 99.0) / 100.0
+    }
+}
+
+/// An owned snapshot of the devices and nodes in the server state.
+#[derive(Debug)]
+pub struct Topology {
+    pub output_devices: Vec<CrasIodevInfo>,
+    pub input_devices: Vec<CrasIodevInfo>,
+    pub output_nodes: Vec<CrasIonodeInfo>,
+    pub input_nodes: Vec<CrasIonodeInfo>,
+    // The update count of the server state when the snapshot was taken.
+    update_count: u32,
+}
+
+impl Topology {
+    /// Checks whether the server state changed after `previous` was taken.
+    ///
+    /// This only compares the update counts of the server state, so it is cheap,
+    /// but the server updates the count on any change of the state, e.g. the
+    /// system volume, so it may return `true` when the lists are unchanged.
+    pub fn changed_since(&self, previous: &Topology) -> bool {
+        self.update_count != previous.update_count
+    }
+}
+
+/// The audio features enabled or supported by the server, as reported in the
+/// server state.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FeatureFlags {
+    /// Whether system echo cancellation is supported.
+    pub aec_supported: bool,
+    /// Whether Bluetooth wideband speech is enabled.
+    pub bt_wbs_enabled: bool,
+    /// Whether enabling Bluetooth HFP offload is determined by a Finch flag.
+    pub bt_hfp_offload_finch_applied: bool,
+    /// Whether Bluetooth wideband speech mic is deprioritized as default input.
+    pub deprioritize_bt_wbs_mic: bool,
+    /// Whether noise cancellation is enabled.
+    pub noise_cancellation_enabled: bool,
+    /// Whether noise cancellation is supported on DSP by at least one input node.
+    pub dsp_noise_cancellation_supported: bool,
+    /// Whether the block/unblock mechanism of noise cancellation is bypassed.
+    pub bypass_block_noise_cancellation: bool,
+    /// Whether hotword detection is paused when the system suspends.
+    pub hotword_pause_at_suspend: bool,
+    /// Whether system noise suppression is supported.
+    pub ns_supported: bool,
+    /// Whether system gain control is supported.
+    pub agc_supported: bool,
+    /// Whether using the hardware provided echo reference in APM is disabled.
+    pub hw_echo_ref_disabled: bool,
+    /// Whether system echo cancellation on DSP is supported.
+    pub aec_on_dsp_supported: bool,
+    /// Whether system noise suppression on DSP is supported.
+    pub ns_on_dsp_supported: bool,
+    /// Whether system gain control on DSP is supported.
+    pub agc_on_dsp_supported: bool,
+    /// Whether UI gains are force respected.
+    pub force_respect_ui_gains: bool,
+    /// Whether noise cancellation is integrated without echo cancellation on DSP.
+    pub nc_standalone_mode: bool,
+}
+
 /// A structure that points to RO shared memory area - `cras_server_state`
 /// The structure is created from a shared memory fd which contains the structure.
 #[derive(Debug)]
 pub struct CrasServerState<'a> {
     addr: *mut libc::c_void,
     volume: VolatileRef<'a, u32>,
+    min_volume_dbfs: VolatileRef<'a, i32>,
+    max_volume_dbfs: VolatileRef<'a, i32>,
     mute: VolatileRef<'a, i32>,
+    user_mute: VolatileRef<'a, i32>,
+    capture_mute: VolatileRef<'a, i32>,
+    capture_mute_locked: VolatileRef<'a, i32>,
     num_output_devs: VolatileRef<'a, u32>,
     output_devs: VolatileSlice<'a>,
     num_input_devs: VolatileRef<'a, u32>,
@@ -561,10 +761,29 @@ pub struct CrasServerState<'a> {
     output_nodes: VolatileSlice<'a>,
     input_nodes: VolatileSlice<'a>,
     update_count: VolatileRef<'a, u32>,
+    num_attached_clients: VolatileRef<'a, u32>,
+    client_info: VolatileSlice<'a>,
     debug_info_num_devs: VolatileRef<'a, u32>,
     debug_info_devs: VolatileSlice<'a>,
     debug_info_num_streams: VolatileRef<'a, u32>,
     debug_info_streams: VolatileSlice<'a>,
+    default_output_buffer_size: VolatileRef<'a, i32>,
+    aec_supported: VolatileRef<'a, i32>,
+    bt_wbs_enabled: VolatileRef<'a, i32>,
+    bt_hfp_offload_finch_applied: VolatileRef<'a, i32>,
+    deprioritize_bt_wbs_mic: VolatileRef<'a, i32>,
+    noise_cancellation_enabled: VolatileRef<'a, i32>,
+    dsp_noise_cancellation_supported: VolatileRef<'a, i32>,
+    bypass_block_noise_cancellation: VolatileRef<'a, i32>,
+    hotword_pause_at_suspend: VolatileRef<'a, i32>,
+    ns_supported: VolatileRef<'a, i32>,
+    agc_supported: VolatileRef<'a, i32>,
+    hw_echo_ref_disabled: VolatileRef<'a, i32>,
+    aec_on_dsp_supported: VolatileRef<'a, i32>,
+    ns_on_dsp_supported: VolatileRef<'a, i32>,
+    agc_on_dsp_supported: VolatileRef<'a, i32>,
+    force_respect_ui_gains: VolatileRef<'a, i32>,
+    nc_standalone_mode: VolatileRef<'a, i32>,
 }
 
 // It is safe to send server_state between threads as this struct has exclusive
@@ -603,7 +822,12 @@ impl<'a> CrasServerState<'a> {
             Ok(CrasServerState {
                 addr: addr.as_ptr() as *mut libc::c_void,
                 volume: vref_from_addr!(addr, volume),
+                min_volume_dbfs: vref_from_addr!(addr, min_volume_dBFS),
+                max_volume_dbfs: vref_from_addr!(addr, max_volume_dBFS),
                 mute: vref_from_addr!(addr, mute),
+                user_mute: vref_from_addr!(addr, user_mute),
+                capture_mute: vref_from_addr!(addr, capture_mute),
+                capture_mute_locked: vref_from_addr!(addr, capture_mute_locked),
                 num_output_devs: vref_from_addr!(addr, num_output_devs),
                 num_input_devs: vref_from_addr!(addr, num_input_devs),
                 output_devs: vslice_from_addr!(addr, output_devs),
@@ -613,10 +837,35 @@ impl<'a> CrasServerState<'a> {
                 output_nodes: vslice_from_addr!(addr, output_nodes),
                 input_nodes: vslice_from_addr!(addr, input_nodes),
                 update_count: vref_from_addr!(addr, update_count),
+                num_attached_clients: vref_from_addr!(addr, num_attached_clients),
+                client_info: vslice_from_addr!(addr, client_info),
                 debug_info_num_devs: vref_from_addr!(addr, audio_debug_info.num_devs),
                 debug_info_devs: vslice_from_addr!(addr, audio_debug_info.devs),
                 debug_info_num_streams: vref_from_addr!(addr, audio_debug_info.num_streams),
                 debug_info_streams: vslice_from_addr!(addr, audio_debug_info.streams),
+                default_output_buffer_size: vref_from_addr!(addr, default_output_buffer_size),
+                aec_supported: vref_from_addr!(addr, aec_supported),
+                bt_wbs_enabled: vref_from_addr!(addr, bt_wbs_enabled),
+                bt_hfp_offload_finch_applied: vref_from_addr!(addr, bt_hfp_offload_finch_applied),
+                deprioritize_bt_wbs_mic: vref_from_addr!(addr, deprioritize_bt_wbs_mic),
+                noise_cancellation_enabled: vref_from_addr!(addr, noise_cancellation_enabled),
+                dsp_noise_cancellation_supported: vref_from_addr!(
+                    addr,
+                    dsp_noise_cancellation_supported
+                ),
+                bypass_block_noise_cancellation: vref_from_addr!(
+                    addr,
+                    bypass_block_noise_cancellation
+                ),
+                hotword_pause_at_suspend: vref_from_addr!(addr, hotword_pause_at_suspend),
+                ns_supported: vref_from_addr!(addr, ns_supported),
+                agc_supported: vref_from_addr!(addr, agc_supported),
+                hw_echo_ref_disabled: vref_from_addr!(addr, hw_echo_ref_disabled),
+                aec_on_dsp_supported: vref_from_addr!(addr, aec_on_dsp_supported),
+                ns_on_dsp_supported: vref_from_addr!(addr, ns_on_dsp_supported),
+                agc_on_dsp_supported: vref_from_addr!(addr, agc_on_dsp_supported),
+                force_respect_ui_gains: vref_from_addr!(addr, force_respect_ui_gains),
+                nc_standalone_mode: vref_from_addr!(addr, nc_standalone_mode),
             })
         }
     }
@@ -635,6 +884,88 @@ impl<'a> CrasServerState<'a> {
         self.mute.load() != 0
     }
 
+    /// Gets the user mute.
+    ///
+    /// Read the current value for the mute set by the user from shared memory. The
+    /// output is silent if either the system mute or the user mute is set.
+    pub fn get_user_mute(&self) -> bool {
+        self.user_mute.load() != 0
+    }
+
+    /// Gets the system capture mute.
+    ///
+    /// Read the current value for system capture mute from shared memory.
+    pub fn get_system_capture_mute(&self) -> bool {
+        self.capture_mute.load() != 0
+    }
+
+    /// Gets whether the system capture mute is locked.
+    ///
+    /// Read the current value for system capture mute lock from shared memory.
+    /// The server ignores requests to change the capture mute while it is locked.
+    pub fn get_system_capture_mute_locked(&self) -> bool {
+        self.capture_mute_locked.load() != 0
+    }
+
+    /// Gets the system volume scalar, its volume in dB and the system mute.
+    ///
+    /// All the values are read from shared memory in one synchronized read, so
+    /// they are consistent with each other.
+    pub fn get_system_volume_state(&self) -> VolumeState {
+        let (scalar, min_volume_dbfs, max_volume_dbfs, mute) = self.synchronized_state_read(|| {
+            (
+                self.volume.load(),
+                self.min_volume_dbfs.load(),
+                self.max_volume_dbfs.load(),
+                self.mute.load(),
+            )
+        });
+        VolumeState {
+            scalar,
+            db: VolumeState::scalar_to_db(scalar, min_volume_dbfs, max_volume_dbfs),
+            muted: mute != 0,
+        }
+    }
+
+    /// Gets whether running echo cancellation on DSP is supported by the system.
+    pub fn get_aec_on_dsp_supported(&self) -> bool {
+        self.aec_on_dsp_supported.load() != 0
+    }
+
+    /// Gets whether running noise suppression on DSP is supported by the system.
+    pub fn get_ns_on_dsp_supported(&self) -> bool {
+        self.ns_on_dsp_supported.load() != 0
+    }
+
+    /// Gets whether running gain control on DSP is supported by the system.
+    pub fn get_agc_on_dsp_supported(&self) -> bool {
+        self.agc_on_dsp_supported.load() != 0
+    }
+
+    /// Gets the audio features enabled or supported by the server.
+    ///
+    /// All the flags are read from shared memory in one synchronized read.
+    pub fn get_feature_flags(&self) -> FeatureFlags {
+        self.synchronized_state_read(|| FeatureFlags {
+            aec_supported: self.aec_supported.load() != 0,
+            bt_wbs_enabled: self.bt_wbs_enabled.load() != 0,
+            bt_hfp_offload_finch_applied: self.bt_hfp_offload_finch_applied.load() != 0,
+            deprioritize_bt_wbs_mic: self.deprioritize_bt_wbs_mic.load() != 0,
+            noise_cancellation_enabled: self.noise_cancellation_enabled.load() != 0,
+            dsp_noise_cancellation_supported: self.dsp_noise_cancellation_supported.load() != 0,
+            bypass_block_noise_cancellation: self.bypass_block_noise_cancellation.load() != 0,
+            hotword_pause_at_suspend: self.hotword_pause_at_suspend.load() != 0,
+            ns_supported: self.ns_supported.load() != 0,
+            agc_supported: self.agc_supported.load() != 0,
+            hw_echo_ref_disabled: self.hw_echo_ref_disabled.load() != 0,
+            aec_on_dsp_supported: self.aec_on_dsp_supported.load() != 0,
+            ns_on_dsp_supported: self.ns_on_dsp_supported.load() != 0,
+            agc_on_dsp_supported: self.agc_on_dsp_supported.load() != 0,
+            force_respect_ui_gains: self.force_respect_ui_gains.load() != 0,
+            nc_standalone_mode: self.nc_standalone_mode.load() != 0,
+        })
+    }
+
     /// Runs a closure safely such that it can be sure that the server state
     /// was not updated during the read.
     /// This can be used for an "atomic" read of non-atomic data from the
@@ -677,6 +1008,31 @@ impl<'a> CrasServerState<'a> {
         }
     }
 
+    /// Gets the default output buffer size in frames.
+    pub fn get_default_output_buffer_size(&self) -> i32 {
+        self.default_output_buffer_size.load()
+    }
+
+    /// Gets the number of the currently attached output devices.
+    pub fn get_num_output_devs(&self) -> u32 {
+        clamp_count("num_output_devs", self.num_output_devs.load(), CRAS_MAX_IODEVS) as u32
+    }
+
+    /// Gets the number of the currently attached input devices.
+    pub fn get_num_input_devs(&self) -> u32 {
+        clamp_count("num_input_devs", self.num_input_devs.load(), CRAS_MAX_IODEVS) as u32
+    }
+
+    /// Gets the number of the currently attached output nodes.
+    pub fn get_num_output_nodes(&self) -> u32 {
+        clamp_count("num_output_nodes", self.num_output_nodes.load(), CRAS_MAX_IONODES) as u32
+    }
+
+    /// Gets the number of the currently attached input nodes.
+    pub fn get_num_input_nodes(&self) -> u32 {
+        clamp_count("num_input_nodes", self.num_input_nodes.load(), CRAS_MAX_IONODES) as u32
+    }
+
     /// Gets a list of output devices
     ///
     /// Read a list of the currently attached output devices from shared memory.
@@ -687,7 +1043,7 @@ impl<'a> CrasServerState<'a> {
             self.num_output_devs.load()
         });
         devs.into_iter()
-            .take(num_devs as usize)
+            .take(clamp_count("num_output_devs", num_devs, CRAS_MAX_IODEVS))
             .map(CrasIodevInfo::from)
     }
 
@@ -701,7 +1057,7 @@ impl<'a> CrasServerState<'a> {
             self.num_input_devs.load()
         });
         devs.into_iter()
-            .take(num_devs as usize)
+            .take(clamp_count("num_input_devs", num_devs, CRAS_MAX_IODEVS))
             .map(CrasIodevInfo::from)
     }
 
@@ -716,7 +1072,7 @@ impl<'a> CrasServerState<'a> {
         });
         nodes
             .into_iter()
-            .take(num_nodes as usize)
+            .take(clamp_count("num_output_nodes", num_nodes, CRAS_MAX_IONODES))
             .map(CrasIonodeInfo::from)
     }
 
@@ -731,10 +1087,93 @@ impl<'a> CrasServerState<'a> {
         });
         nodes
             .into_iter()
-            .take(num_nodes as usize)
+            .take(clamp_count("num_input_nodes", num_nodes, CRAS_MAX_IONODES))
             .map(CrasIonodeInfo::from)
     }
 
+    /// Checks whether `node_id` is in the list of attached output nodes.
+    pub fn has_output_node(&self, node_id: CrasIodevNodeId) -> bool {
+        self.output_nodes().any(|node| {
+            node.iodev_index == node_id.iodev_index && node.ionode_index == node_id.ionode_index
+        })
+    }
+
+    /// Checks whether `node_id` is in the list of attached input nodes.
+    pub fn has_input_node(&self, node_id: CrasIodevNodeId) -> bool {
+        self.input_nodes().any(|node| {
+            node.iodev_index == node_id.iodev_index && node.ionode_index == node_id.ionode_index
+        })
+    }
+
+    /// Gets a snapshot of the devices and nodes
+    ///
+    /// Read all the device and node lists from shared memory in one synchronized
+    /// read.
+    pub fn get_topology(&self) -> Topology {
+        let mut output_devs: Vec<cras_iodev_info> =
+            vec![Default::default(); CRAS_MAX_IODEVS as usize];
+        let mut input_devs: Vec<cras_iodev_info> =
+            vec![Default::default(); CRAS_MAX_IODEVS as usize];
+        let mut output_nodes: Vec<cras_ionode_info> =
+            vec![Default::default(); CRAS_MAX_IONODES as usize];
+        let mut input_nodes: Vec<cras_ionode_info> =
+            vec![Default::default(); CRAS_MAX_IONODES as usize];
+        let (num_output_devs, num_input_devs, num_output_nodes, num_input_nodes, update_count) =
+            self.synchronized_state_read(|| {
+                self.output_devs.copy_to(&mut output_devs);
+                self.input_devs.copy_to(&mut input_devs);
+                self.output_nodes.copy_to(&mut output_nodes);
+                self.input_nodes.copy_to(&mut input_nodes);
+                (
+                    self.num_output_devs.load(),
+                    self.num_input_devs.load(),
+                    self.num_output_nodes.load(),
+                    self.num_input_nodes.load(),
+                    self.update_count.load(),
+                )
+            });
+        Topology {
+            output_devices: output_devs
+                .into_iter()
+                .take(clamp_count("num_output_devs", num_output_devs, CRAS_MAX_IODEVS))
+                .map(CrasIodevInfo::from)
+                .collect(),
+            input_devices: input_devs
+                .into_iter()
+                .take(clamp_count("num_input_devs", num_input_devs, CRAS_MAX_IODEVS))
+                .map(CrasIodevInfo::from)
+                .collect(),
+            output_nodes: output_nodes
+                .into_iter()
+                .take(clamp_count("num_output_nodes", num_output_nodes, CRAS_MAX_IONODES))
+                .map(CrasIonodeInfo::from)
+                .collect(),
+            input_nodes: input_nodes
+                .into_iter()
+                .take(clamp_count("num_input_nodes", num_input_nodes, CRAS_MAX_IONODES))
+                .map(CrasIonodeInfo::from)
+                .collect(),
+            update_count,
+        }
+    }
+
+    /// Gets a list of attached clients
+    ///
+    /// Read the list of the clients attached to the server from shared memory.
+    /// The server lists at most `CRAS_MAX_ATTACHED_CLIENTS` clients.
+    pub fn attached_clients(&self) -> impl Iterator<Item = CrasAttachedClientInfo> {
+        let mut clients: Vec<cras_attached_client_info> =
+            vec![Default::default(); CRAS_MAX_ATTACHED_CLIENTS as usize];
+        let num_clients = self.synchronized_state_read(|| {
+            self.client_info.copy_to(&mut clients);
+            self.num_attached_clients.load()
+        });
+        clients
+            .into_iter()
+            .take(clamp_count("num_attached_clients", num_clients, CRAS_MAX_ATTACHED_CLIENTS))
+            .map(CrasAttachedClientInfo::from)
+    }
+
     /// Get audio debug info
     ///
     /// Loads the server's audio_debug_info struct and converts it into an
@@ -756,16 +1195,32 @@ impl<'a> CrasServerState<'a> {
         });
         let dev_info = devs
             .into_iter()
-            .take(num_devs as usize)
+            .take(clamp_count("debug num_devs", num_devs, MAX_DEBUG_DEVS))
             .map(AudioDevDebugInfo::from)
             .collect();
         let stream_info = streams
             .into_iter()
-            .take(num_streams as usize)
+            .take(clamp_count("debug num_streams", num_streams, MAX_DEBUG_STREAMS))
             .map(AudioStreamDebugInfo::try_from)
             .collect::<Result<Vec<_>, _>>()?;
         Ok(AudioDebugInfo::new(dev_info, stream_info))
     }
+
+    /// Copies the raw bytes of the server state, `size_of::<cras_server_state>()`
+    /// bytes laid out as the `cras_server_state` struct of the server.
+    ///
+    /// The copy is not synchronized with the `update_count` of the server, so it
+    /// may be torn if the server writes concurrently. This is a best-effort
+    /// snapshot for diagnostics.
+    pub fn dump_raw(&self) -> Vec<u8> {
+        let size = mem::size_of::<cras_server_state>();
+        let mut bytes = vec![0u8; size];
+        // Safe because `addr` is mapped with a `cras_server_state` for the lifetime
+        // of this struct.
+        let state = unsafe { VolatileSlice::from_raw_parts(self.addr as *mut u8, size) };
+        state.copy_to(&mut bytes);
+        bytes
+    }
 }
 
 impl<'a> Drop for CrasServerState<'a> {
@@ -873,6 +1328,11 @@ impl CrasShmFd {
     pub unsafe fn new(fd: libc::c_int, size: usize) -> CrasShmFd {
         CrasShmFd { fd, size }
     }
+
+    /// Gets the size of the shared memory area.
+    pub fn size(&self) -> usize {
+        self.size
+    }
 }
 
 impl AsRawFd for CrasShmFd {
@@ -1029,6 +1489,62 @@ mod tests {
         assert_eq!(header.read_offset[0].load(), 0);
     }
 
+    #[test]
+    fn cras_audio_header_has_read_frames_test() {
+        let mut header = create_cras_audio_header(20);
+        header.frame_size.store(2);
+        header.used_size.store(10);
+        header.write_offset[0].store(10);
+        assert!(!header.has_read_frames());
+        header
+            .commit_read_frames(1)
+            .expect("Failed to commit read frames.");
+        assert!(header.has_read_frames());
+        header
+            .commit_read_frames(4)
+            .expect("Failed to commit read frames.");
+        assert!(header.has_read_frames());
+    }
+
+    #[test]
+    fn cras_audio_header_discard_readable_frames_test() {
+        let mut header = create_cras_audio_header(20);
+        header.frame_size.store(2);
+        header.used_size.store(10);
+        header.read_offset[0].store(2);
+        header.write_offset[0].store(10);
+        header.write_offset[1].store(6);
+        let frames = header
+            .discard_readable_frames()
+            .expect("Failed to discard readable frames.");
+        assert_eq!(frames, 7);
+        assert_eq!(header.get_read_buf_idx(), 0);
+        assert_eq!(header.get_readable_frames().unwrap(), 0);
+    }
+
+    #[test]
+    fn cras_audio_header_volume_scaler_test() {
+        let mut header = create_cras_audio_header(20);
+        header.set_volume_scaler(0.5);
+        assert_eq!(header.get_volume_scaler(), 0.5);
+        header.set_volume_scaler(1.5);
+        assert_eq!(header.get_volume_scaler(), 1.0);
+        header.set_volume_scaler(-1.0);
+        assert_eq!(header.get_volume_scaler(), 0.0);
+    }
+
+    #[test]
+    fn cras_audio_header_mute_test() {
+        let mut header = create_cras_audio_header(20);
+        assert!(!header.get_mute());
+        header.set_volume_scaler(0.5);
+        header.set_mute(true);
+        assert!(header.get_mute());
+        assert_eq!(header.get_volume_scaler(), 0.5);
+        header.set_mute(false);
+        assert!(!header.get_mute());
+    }
+
     #[test]
     fn cras_audio_header_get_write_offset_and_len() {
         let header = create_cras_audio_header(30);
@@ -1142,6 +1658,26 @@ mod tests {
         assert!(header.set_buffer_offset(0, 33).is_err());
     }
 
+    #[test]
+    fn cras_audio_header_set_write_buffer_offset() {
+        let mut header = create_cras_audio_header(30);
+        header.frame_size.store(2);
+        header.used_size.store(10);
+        header.buffer_offset[0].store(0);
+        header.buffer_offset[1].store(10);
+
+        header.write_buf_idx.store(1);
+        header
+            .set_write_buffer_offset(20)
+            .expect("Failed to set write buffer offset.");
+        assert_eq!(header.buffer_offset[0].load(), 0);
+        assert_eq!(header.buffer_offset[1].load(), 20);
+        assert_eq!(header.get_write_offset_and_len().unwrap(), (20, 10));
+
+        // The write buffer must not overlap the other buffer.
+        assert!(header.set_write_buffer_offset(5).is_err());
+    }
+
     #[test]
     fn create_header_and_buffers_test() {
         let header_fd = cras_audio_header_fd();
@@ -1197,6 +1733,9 @@ mod tests {
                 state.state_version = CRAS_SERVER_STATE_VERSION;
                 state.volume = 47;
                 state.mute = 1;
+                state.user_mute = 1;
+                state.capture_mute = 1;
+                state.capture_mute_locked = 1;
             }
             libc::munmap(addr, size);
         };
@@ -1205,6 +1744,224 @@ mod tests {
             CrasServerState::try_new(state_fd).expect("try_new failed for valid server_state fd");
         assert_eq!(state.get_system_volume(), 47);
         assert!(state.get_system_mute());
+        assert!(state.get_user_mute());
+        assert!(state.get_system_capture_mute());
+        assert!(state.get_system_capture_mute_locked());
+    }
+
+    #[test]
+    fn cras_server_state_volume_state() {
+        let size = mem::size_of::<cras_server_state>();
+        let shm = create_shm(size);
+        unsafe {
+            let addr = cras_mmap(size, libc::PROT_WRITE, shm.as_raw_fd())
+                .expect("failed to mmap state shm");
+            {
+                let state: &mut cras_server_state = &mut *(addr as *mut cras_server_state);
+                state.state_version = CRAS_SERVER_STATE_VERSION;
+                state.volume = 100;
+                state.min_volume_dBFS = -5000;
+                state.max_volume_dBFS = -100;
+                state.mute = 1;
+            }
+            libc::munmap(addr, size);
+        };
+        let state_fd = unsafe { CrasServerStateShmFd::new(shm.into_raw_fd()) };
+        let state =
+            CrasServerState::try_new(state_fd).expect("try_new failed for valid server_state fd");
+        assert_eq!(
+            state.get_system_volume_state(),
+            VolumeState {
+                scalar: 100,
+                db: -1.0,
+                muted: true,
+            }
+        );
+        assert_eq!(VolumeState::scalar_to_db(1, -5000, -100), -50.0);
+        assert_eq!(VolumeState::scalar_to_db(0, -5000, -100), f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn cras_server_state_dump_raw() {
+        let size = mem::size_of::<cras_server_state>();
+        let shm = create_shm(size);
+        unsafe {
+            let addr = cras_mmap(size, libc::PROT_WRITE, shm.as_raw_fd())
+                .expect("failed to mmap state shm");
+            {
+                let state: &mut cras_server_state = &mut *(addr as *mut cras_server_state);
+                state.state_version = CRAS_SERVER_STATE_VERSION;
+                state.volume = 47;
+            }
+            libc::munmap(addr, size);
+        };
+        let state_fd = unsafe { CrasServerStateShmFd::new(shm.into_raw_fd()) };
+        let state =
+            CrasServerState::try_new(state_fd).expect("try_new failed for valid server_state fd");
+        let bytes = state.dump_raw();
+        assert_eq!(bytes.len(), size);
+        let dumped: cras_server_state =
+            unsafe { ptr::read_unaligned(bytes.as_ptr() as *const cras_server_state) };
+        assert_eq!(dumped.state_version, CRAS_SERVER_STATE_VERSION);
+        assert_eq!(dumped.volume, 47);
+    }
+
+    #[test]
+    fn cras_server_state_feature_flags() {
+        let size = mem::size_of::<cras_server_state>();
+        let shm = create_shm(size);
+        unsafe {
+            let addr = cras_mmap(size, libc::PROT_WRITE, shm.as_raw_fd())
+                .expect("failed to mmap state shm");
+            {
+                let state: &mut cras_server_state = &mut *(addr as *mut cras_server_state);
+                state.state_version = CRAS_SERVER_STATE_VERSION;
+                state.noise_cancellation_enabled = 1;
+                state.aec_on_dsp_supported = 1;
+            }
+            libc::munmap(addr, size);
+        };
+        let state_fd = unsafe { CrasServerStateShmFd::new(shm.into_raw_fd()) };
+        let state =
+            CrasServerState::try_new(state_fd).expect("try_new failed for valid server_state fd");
+        assert_eq!(
+            state.get_feature_flags(),
+            FeatureFlags {
+                noise_cancellation_enabled: true,
+                aec_on_dsp_supported: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn cras_server_state_oversized_count() {
+        let size = mem::size_of::<cras_server_state>();
+        let shm = create_shm(size);
+        unsafe {
+            let addr = cras_mmap(size, libc::PROT_WRITE, shm.as_raw_fd())
+                .expect("failed to mmap state shm");
+            {
+                let state: &mut cras_server_state = &mut *(addr as *mut cras_server_state);
+                state.state_version = CRAS_SERVER_STATE_VERSION;
+                state.num_output_devs = CRAS_MAX_IODEVS + 1;
+                state.num_input_nodes = u32::MAX;
+            }
+            libc::munmap(addr, size);
+        };
+        let state_fd = unsafe { CrasServerStateShmFd::new(shm.into_raw_fd()) };
+        let state =
+            CrasServerState::try_new(state_fd).expect("try_new failed for valid server_state fd");
+        assert_eq!(state.get_num_output_devs(), CRAS_MAX_IODEVS);
+        assert_eq!(state.output_devices().count(), CRAS_MAX_IODEVS as usize);
+        assert_eq!(state.get_num_input_nodes(), CRAS_MAX_IONODES);
+        assert_eq!(state.input_nodes().count(), CRAS_MAX_IONODES as usize);
+        assert_eq!(state.get_num_output_nodes(), 0);
+    }
+
+    #[test]
+    fn cras_server_state_topology() {
+        let size = mem::size_of::<cras_server_state>();
+        let shm = create_shm(size);
+        let addr = unsafe {
+            let addr = cras_mmap(size, libc::PROT_WRITE, shm.as_raw_fd())
+                .expect("failed to mmap state shm");
+            let state: &mut cras_server_state = &mut *(addr as *mut cras_server_state);
+            state.state_version = CRAS_SERVER_STATE_VERSION;
+            state.num_output_devs = 1;
+            state.num_input_nodes = 2;
+            state.update_count = 2;
+            addr
+        };
+        let state_fd = unsafe { CrasServerStateShmFd::new(shm.into_raw_fd()) };
+        let state =
+            CrasServerState::try_new(state_fd).expect("try_new failed for valid server_state fd");
+        let topology = state.get_topology();
+        assert_eq!(topology.output_devices.len(), 1);
+        assert_eq!(topology.input_devices.len(), 0);
+        assert_eq!(topology.output_nodes.len(), 0);
+        assert_eq!(topology.input_nodes.len(), 2);
+        assert!(!state.get_topology().changed_since(&topology));
+
+        unsafe {
+            let server_state: &mut cras_server_state = &mut *(addr as *mut cras_server_state);
+            server_state.update_count = 4;
+            libc::munmap(addr, size);
+        }
+        assert!(state.get_topology().changed_since(&topology));
+    }
+
+    #[test]
+    fn cras_server_state_has_node() {
+        let size = mem::size_of::<cras_server_state>();
+        let shm = create_shm(size);
+        unsafe {
+            let addr = cras_mmap(size, libc::PROT_WRITE, shm.as_raw_fd())
+                .expect("failed to mmap state shm");
+            {
+                let state: &mut cras_server_state = &mut *(addr as *mut cras_server_state);
+                state.state_version = CRAS_SERVER_STATE_VERSION;
+                state.num_output_nodes = 1;
+                state.output_nodes[0].iodev_idx = 3;
+                state.output_nodes[0].ionode_idx = 1;
+                state.num_input_nodes = 1;
+                state.input_nodes[0].iodev_idx = 4;
+            }
+            libc::munmap(addr, size);
+        };
+        let state_fd = unsafe { CrasServerStateShmFd::new(shm.into_raw_fd()) };
+        let state =
+            CrasServerState::try_new(state_fd).expect("try_new failed for valid server_state fd");
+        let node_id = |iodev_index, ionode_index| CrasIodevNodeId {
+            iodev_index,
+            ionode_index,
+        };
+        assert!(state.has_output_node(node_id(3, 1)));
+        assert!(!state.has_output_node(node_id(3, 0)));
+        assert!(!state.has_output_node(node_id(4, 0)));
+        assert!(state.has_input_node(node_id(4, 0)));
+        assert!(!state.has_input_node(node_id(3, 1)));
+    }
+
+    #[test]
+    fn cras_server_state_attached_clients() {
+        let size = mem::size_of::<cras_server_state>();
+        let shm = create_shm(size);
+        unsafe {
+            let addr = cras_mmap(size, libc::PROT_WRITE, shm.as_raw_fd())
+                .expect("failed to mmap state shm");
+            {
+                let state: &mut cras_server_state = &mut *(addr as *mut cras_server_state);
+                state.state_version = CRAS_SERVER_STATE_VERSION;
+                state.num_attached_clients = 2;
+                state.client_info[0].id = 3;
+                state.client_info[0].pid = 100;
+                state.client_info[1].id = 5;
+                state.client_info[1].uid = 600;
+            }
+            libc::munmap(addr, size);
+        };
+        let state_fd = unsafe { CrasServerStateShmFd::new(shm.into_raw_fd()) };
+        let state =
+            CrasServerState::try_new(state_fd).expect("try_new failed for valid server_state fd");
+        let clients: Vec<_> = state.attached_clients().collect();
+        assert_eq!(
+            clients,
+            vec![
+                CrasAttachedClientInfo {
+                    id: 3,
+                    pid: 100,
+                    uid: 0,
+                    gid: 0,
+                },
+                CrasAttachedClientInfo {
+                    id: 5,
+                    pid: 0,
+                    uid: 600,
+                    gid: 0,
+                },
+            ]
+        );
     }
 
     #[test]
@@ -4,14 +4,26 @@
 use std::cmp::min;
 use std::io;
 use std::marker::PhantomData;
-use std::time::Duration;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{
+    channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError,
+};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use std::{error, fmt};
 
 use audio_streams::{
     capture::{CaptureBuffer, CaptureBufferStream},
-    BoxError, BufferCommit, PlaybackBuffer, PlaybackBufferStream,
+    BoxError, BufferCommit, PlaybackBuffer, PlaybackBufferStream, StreamEffect,
+};
+use cras_sys::gen::{
+    cras_audio_format_packed, snd_pcm_format_t, CRAS_AUDIO_MESSAGE_ID, CRAS_CHANNEL,
+    CRAS_STREAM_DIRECTION, CRAS_STREAM_EFFECT,
 };
-use cras_sys::gen::{snd_pcm_format_t, CRAS_AUDIO_MESSAGE_ID, CRAS_STREAM_DIRECTION};
+use cras_sys::AudioDebugInfo;
 use libchromeos::sys::error;
 
 use crate::audio_socket::{AudioMessage, AudioSocket};
@@ -20,8 +32,10 @@ use crate::cras_shm::*;
 
 #[derive(Debug)]
 pub enum Error {
+    EffectChangeUnsupported,
     IoError(io::Error),
     MessageTypeError,
+    StartTimeout(Duration),
 }
 
 impl error::Error for Error {}
@@ -29,8 +43,15 @@ impl error::Error for Error {}
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            Error::EffectChangeUnsupported => write!(
+                f,
+                "Effects of a connected stream cannot be changed, reconnect the stream"
+            ),
             Error::IoError(ref err) => err.fmt(f),
             Error::MessageTypeError => write!(f, "Message type error"),
+            Error::StartTimeout(timeout) => {
+                write!(f, "Stream did not start running within {:?}", timeout)
+            }
         }
     }
 }
@@ -41,11 +62,85 @@ impl From<io::Error> for Error {
     }
 }
 
+/// State events of a `CrasStream`.
+///
+/// CRAS does not send stream state messages to clients; the audio socket of a
+/// stream only carries data messages. These events are derived from the audio
+/// messages read by the stream, so they are only delivered while the stream is
+/// waiting for its next buffer. CRAS does not tell a client when its stream is
+/// moved to another device, so no event is delivered for reattachment. Each event
+/// is delivered at most once per stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamEvent {
+    /// The first audio message of the stream is received from the server.
+    Started,
+    /// The device of a trigger-only stream triggered, and the captured data is
+    /// ready to be read. See `StreamParams::trigger_only`.
+    Triggered,
+    /// The server removed the stream. It either closed the audio socket, or
+    /// reported the given error code in an audio message.
+    Removed(Option<i32>),
+}
+
+/// Callback timing statistics of a stream, as tracked by the server audio thread.
+///
+/// The shared memory header of a stream does not carry any callback timing, so
+/// these values are taken from an audio thread dump. The server only keeps the
+/// longest fetch time and the number of slow or missed callbacks, not an average.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallbackStats {
+    /// The longest time the server waited for the client to fill a buffer.
+    pub max: Duration,
+    /// The number of fetches that were not done in time.
+    pub num_delayed: u32,
+    /// The number of callbacks the client missed entirely.
+    pub num_missed: u32,
+    /// How long the stream has been running.
+    pub runtime: Duration,
+}
+
+/// The statistics of a stream over an interval, see `CrasStream::take_stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamStats {
+    /// The duration of underruns since the previous call, see `underruns`.
+    pub underruns: Duration,
+    /// The number of overruns since the previous call, see `overruns`.
+    pub overruns: u32,
+    /// The callback timing, cumulative since the stream was connected, or `None`
+    /// if the stream is not in the dump.
+    pub callback: Option<CallbackStats>,
+}
+
+/// The format of the samples of a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamFormat {
+    pub frame_rate: u32,
+    pub num_channels: usize,
+    /// The `snd_pcm_format_t` value of the sample format.
+    pub format: i32,
+}
+
+/// The format a stream was requested with and the format the server connected
+/// it with, see `CrasStream::format_negotiation`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatNegotiation {
+    pub requested: StreamFormat,
+    pub actual: StreamFormat,
+}
+
+impl FormatNegotiation {
+    /// Whether the server connected the stream with another format than requested.
+    pub fn is_changed(&self) -> bool {
+        self.requested != self.actual
+    }
+}
+
 /// A trait controls the state of `CrasAudioHeader` and
 /// interacts with server's audio thread through `AudioSocket`.
 pub trait CrasStreamData<'a>: Send {
     // Creates `CrasStreamData` with only `AudioSocket`.
     fn new(audio_sock: AudioSocket, header: CrasAudioHeader<'a>, rate: u32) -> Self;
+    fn header(&self) -> &CrasAudioHeader<'a>;
     fn header_mut(&mut self) -> &mut CrasAudioHeader<'a>;
     fn audio_sock_mut(&mut self) -> &mut AudioSocket;
 }
@@ -66,6 +161,10 @@ impl<'a> CrasStreamData<'a> for CrasPlaybackData<'a> {
         }
     }
 
+    fn header(&self) -> &CrasAudioHeader<'a> {
+        &self.header
+    }
+
     fn header_mut(&mut self) -> &mut CrasAudioHeader<'a> {
         &mut self.header
     }
@@ -127,6 +226,10 @@ impl<'a> CrasStreamData<'a> for CrasCaptureData<'a> {
         }
     }
 
+    fn header(&self) -> &CrasAudioHeader<'a> {
+        &self.header
+    }
+
     fn header_mut(&mut self) -> &mut CrasAudioHeader<'a> {
         &mut self.header
     }
@@ -172,6 +275,12 @@ impl<'a> BufferCommit for CrasCaptureData<'a> {
     }
 }
 
+/// A CRAS client stream.
+///
+/// `block_size` is sent as both the buffer size and the callback threshold of the
+/// stream when it is connected. CRAS has no message to change them for a
+/// connected stream, so adjusting the callback threshold requires disconnecting
+/// and connecting a new stream.
 #[allow(dead_code)]
 pub struct CrasStream<'a, T: CrasStreamData<'a> + BufferCommit> {
     stream_id: u32,
@@ -186,6 +295,19 @@ pub struct CrasStream<'a, T: CrasStreamData<'a> + BufferCommit> {
     /// The `PhantomData` is used by `controls: T`
     phantom: PhantomData<CrasAudioHeader<'a>>,
     audio_buffer: CrasAudioBuffer,
+    started: bool,
+    trigger_only: bool,
+    triggered: bool,
+    removed: bool,
+    event_sender: Sender<StreamEvent>,
+    event_receiver: Option<Receiver<StreamEvent>>,
+    underrun_baseline: Duration,
+    overrun_baseline: u32,
+    channel_layout: Vec<CRAS_CHANNEL>,
+    // The format in the connect reply of the server.
+    actual_format: Option<StreamFormat>,
+    // Tells the client which created the stream whether it is still alive.
+    _restore_token: Option<Arc<()>>,
 }
 
 impl<'a, T: CrasStreamData<'a> + BufferCommit> CrasStream<'a, T> {
@@ -207,6 +329,7 @@ impl<'a, T: CrasStreamData<'a> + BufferCommit> CrasStream<'a, T> {
         samples_fd: CrasShmFd,
     ) -> Result<Self, Error> {
         let (header, audio_buffer) = create_header_and_buffers(header_fd, samples_fd)?;
+        let (event_sender, event_receiver) = channel();
 
         Ok(Self {
             stream_id,
@@ -219,11 +342,299 @@ impl<'a, T: CrasStreamData<'a> + BufferCommit> CrasStream<'a, T> {
             controls: T::new(audio_sock, header, rate),
             phantom: PhantomData,
             audio_buffer,
+            started: false,
+            trigger_only: false,
+            triggered: false,
+            removed: false,
+            event_sender,
+            event_receiver: Some(event_receiver),
+            underrun_baseline: Duration::ZERO,
+            overrun_baseline: 0,
+            channel_layout: Vec::new(),
+            actual_format: None,
+            _restore_token: None,
         })
     }
 
+    /// Gets the server stream id of this stream.
+    pub fn stream_id(&self) -> u32 {
+        self.stream_id
+    }
+
+    /// Gets the callback timing statistics of this stream from an audio thread dump.
+    ///
+    /// `info` is usually obtained from `CrasClient::get_audio_debug_info` right
+    /// before calling this.
+    ///
+    /// # Returns
+    /// The statistics, or `None` if the stream is not in the dump.
+    pub fn callback_stats(&self, info: &AudioDebugInfo) -> Option<CallbackStats> {
+        info.streams
+            .iter()
+            .find(|s| s.stream_id == u64::from(self.stream_id))
+            .map(|s| CallbackStats {
+                max: s.longest_fetch,
+                num_delayed: s.num_delayed_fetches,
+                num_missed: s.num_missed_cb,
+                runtime: s.runtime,
+            })
+    }
+
+    /// Gets the effects the server applies to this stream from an audio thread dump
+    /// `info`, taken by `CrasClient::get_audio_debug_info`.
+    ///
+    /// The server only processes capture streams, so a playback stream has no
+    /// effects. The `DSP_*_ALLOWED` bits are kept as requested, the effects they
+    /// allow are still reported by their `APM_*` bits whether they run on DSP or
+    /// not.
+    ///
+    /// # Returns
+    /// The effects, or `None` if the stream is not in the dump.
+    pub fn active_effects(&self, info: &AudioDebugInfo) -> Option<CRAS_STREAM_EFFECT> {
+        info.streams
+            .iter()
+            .find(|s| s.stream_id == u64::from(self.stream_id))
+            .map(|s| CRAS_STREAM_EFFECT(s.effects as _))
+    }
+
+    /// Gets the index of the device this stream is attached to from an audio thread
+    /// dump `info`, taken by `CrasClient::get_audio_debug_info`, if the stream is
+    /// pinned.
+    ///
+    /// The connect reply does not carry the device of the stream, so the dump is
+    /// the only place the server reports it. The index is the device the stream
+    /// runs on, which is the device it was pinned to unless the server moved it.
+    ///
+    /// # Returns
+    /// The device index, or `None` if the stream follows the default device or is
+    /// not in the dump.
+    pub fn pinned_device(&self, info: &AudioDebugInfo) -> Option<u32> {
+        info.streams
+            .iter()
+            .find(|s| s.stream_id == u64::from(self.stream_id) && s.is_pinned)
+            .map(|s| s.dev_idx)
+    }
+
+    /// Whether echo cancellation is applied to this stream, see `active_effects`.
+    pub fn is_aec_active(&self, info: &AudioDebugInfo) -> bool {
+        self.is_effect_active(info, CRAS_STREAM_EFFECT::APM_ECHO_CANCELLATION)
+    }
+
+    /// Whether noise suppression is applied to this stream, see `active_effects`.
+    pub fn is_ns_active(&self, info: &AudioDebugInfo) -> bool {
+        self.is_effect_active(info, CRAS_STREAM_EFFECT::APM_NOISE_SUPRESSION)
+    }
+
+    /// Whether automatic gain control is applied to this stream, see
+    /// `active_effects`.
+    pub fn is_agc_active(&self, info: &AudioDebugInfo) -> bool {
+        self.is_effect_active(info, CRAS_STREAM_EFFECT::APM_GAIN_CONTROL)
+    }
+
+    fn is_effect_active(&self, info: &AudioDebugInfo, effect: CRAS_STREAM_EFFECT) -> bool {
+        self.active_effects(info)
+            .map_or(false, |effects| (effects & effect).0 != 0)
+    }
+
+    /// Sets the volume scaler the server applies to the samples of this stream,
+    /// clamped to 0.0..=1.0.
+    ///
+    /// The scaler takes effect for the samples the server mixes after this call.
+    ///
+    /// The scaler applies to all the channels of the stream. The shared memory of
+    /// a stream has no gain per channel and the protocol has no balance control,
+    /// so a balance or a mono downmix has to be applied to the samples before they
+    /// are committed. The only channel control of the server swaps the left and
+    /// right channels of a node, for all its streams.
+    pub fn set_volume_scaler(&mut self, scaler: f32) {
+        self.controls.header_mut().set_volume_scaler(scaler);
+    }
+
+    /// Mutes or unmutes this stream, without affecting other streams or the
+    /// system mute.
+    ///
+    /// The mute is a flag in the shared memory of the stream, separate from the
+    /// volume scaler, so `set_volume_scaler` can be changed while the stream is
+    /// muted and takes effect when it is unmuted. The server only mutes playback
+    /// streams, it ignores the flag of capture streams.
+    pub fn set_muted(&mut self, muted: bool) {
+        self.controls.header_mut().set_mute(muted);
+    }
+
+    /// Whether this stream is muted by `set_muted`.
+    pub fn is_muted(&self) -> bool {
+        self.controls.header().get_mute()
+    }
+
+    /// Changes the effects of this stream.
+    ///
+    /// The CRAS protocol only takes the effects of a stream in its connect
+    /// message, and has no message to change them on a connected stream, so this
+    /// always fails. To change the effects, connect a new stream with the new
+    /// effects before dropping this one, to avoid a gap in the audio.
+    ///
+    /// # Errors
+    /// Always returns `Error::EffectChangeUnsupported`.
+    pub fn set_effects(&mut self, _effects: &[StreamEffect]) -> Result<(), Error> {
+        Err(Error::EffectChangeUnsupported)
+    }
+
+    /// Gets the cumulative duration of zero frames the server filled since the
+    /// stream was connected because the playback buffer was not filled in time.
+    ///
+    /// CRAS reports underruns of a stream as a duration rather than a count.
+    /// Always zero for capture streams.
+    pub fn total_underruns(&self) -> Duration {
+        self.controls.header().get_underrun_duration()
+    }
+
+    /// Gets the duration of underruns since the last `reset_underrun_baseline`.
+    pub fn underruns(&self) -> Duration {
+        self.total_underruns().saturating_sub(self.underrun_baseline)
+    }
+
+    /// Gets the cumulative number of times captured data was overwritten since the
+    /// stream was connected because it was not read in time.
+    ///
+    /// Always zero for playback streams.
+    pub fn total_overruns(&self) -> u32 {
+        self.controls.header().get_num_overruns()
+    }
+
+    /// Gets the number of overruns since the last `reset_underrun_baseline`.
+    pub fn overruns(&self) -> u32 {
+        self.total_overruns().wrapping_sub(self.overrun_baseline)
+    }
+
+    /// Snapshots the current underrun and overrun counters, so that `underruns` and
+    /// `overruns` report the values accumulated after this call.
+    pub fn reset_underrun_baseline(&mut self) {
+        self.underrun_baseline = self.total_underruns();
+        self.overrun_baseline = self.total_overruns();
+    }
+
+    /// Gets the statistics of this stream since the previous call, or since the
+    /// last `reset_underrun_baseline`, and starts a new interval.
+    ///
+    /// The underruns and overruns are read once from the shared memory of the
+    /// stream and become the new baseline, so no update between reading and
+    /// resetting them is lost. The callback timing comes from the audio thread dump
+    /// `info`, see `callback_stats`; the server only keeps it cumulative, so it is
+    /// not reset. The server reports no latency per stream.
+    pub fn take_stats(&mut self, info: &AudioDebugInfo) -> StreamStats {
+        let total_underruns = self.total_underruns();
+        let total_overruns = self.total_overruns();
+        let stats = StreamStats {
+            underruns: total_underruns.saturating_sub(self.underrun_baseline),
+            overruns: total_overruns.wrapping_sub(self.overrun_baseline),
+            callback: self.callback_stats(info),
+        };
+        self.underrun_baseline = total_underruns;
+        self.overrun_baseline = total_overruns;
+        stats
+    }
+
+    /// Takes the receiver of the `StreamEvent`s of this stream.
+    ///
+    /// Events are sent while the stream reads audio messages from the server,
+    /// i.e. while waiting for the next playback or capture buffer.
+    ///
+    /// # Returns
+    /// The receiver, or `None` if it has already been taken.
+    pub fn take_event_receiver(&mut self) -> Option<Receiver<StreamEvent>> {
+        self.event_receiver.take()
+    }
+
+    /// Gets the channel of each sample of a frame, in frame order, as the server
+    /// connected the stream.
+    ///
+    /// The server keeps the layout of the stream as requested, and converts
+    /// between it and the layout of the device, so this is the order the samples
+    /// of the stream must be in. For a 5.1 playback stream the order is FL, FR,
+    /// FC, LFE, RL, RR.
+    pub fn channel_layout(&self) -> Vec<CRAS_CHANNEL> {
+        self.channel_layout.clone()
+    }
+
+    /// Gets the format this stream was requested with and the format the server
+    /// connected it with.
+    ///
+    /// The server converts between the format of a stream and the format of its
+    /// device, e.g. resamples a 44100 stream on a 48000 device, without changing
+    /// the format of the stream, so the formats usually match. The rate of the
+    /// device is reported by `CrasClient::active_output_rate`.
+    pub fn format_negotiation(&self) -> FormatNegotiation {
+        let requested = StreamFormat {
+            frame_rate: self.rate,
+            num_channels: self.num_channels,
+            format: self.format as i32,
+        };
+        FormatNegotiation {
+            requested,
+            actual: self.actual_format.unwrap_or(requested),
+        }
+    }
+
+    // Sets the format and the channel layout the server connected the stream with.
+    pub(crate) fn set_connected_format(&mut self, format: &cras_audio_format_packed) {
+        self.channel_layout = format.channels();
+        self.actual_format = Some(StreamFormat {
+            frame_rate: format.frame_rate,
+            num_channels: format.num_channels as usize,
+            format: format.format,
+        });
+    }
+
+    // Sets the token the client tracks the stream with, to restore it after a
+    // reconnect while the stream is alive.
+    pub(crate) fn set_restore_token(&mut self, token: Arc<()>) {
+        self._restore_token = Some(token);
+    }
+
+    // Marks the stream as connected with the trigger-only flag.
+    pub(crate) fn set_trigger_only(&mut self, trigger_only: bool) {
+        self.trigger_only = trigger_only;
+    }
+
+    // Reads an audio message and sends the `StreamEvent`s it implies.
+    fn read_audio_message(&mut self) -> Result<AudioMessage, Error> {
+        let result = self.controls.audio_sock_mut().read_audio_message();
+        let mut events = Vec::new();
+        match &result {
+            Ok(AudioMessage::Success { id, .. }) => {
+                if !self.started {
+                    self.started = true;
+                    events.push(StreamEvent::Started);
+                }
+                // A trigger-only stream only receives data once it is triggered.
+                if self.trigger_only
+                    && !self.triggered
+                    && *id == CRAS_AUDIO_MESSAGE_ID::AUDIO_MESSAGE_DATA_READY
+                {
+                    self.triggered = true;
+                    events.push(StreamEvent::Triggered);
+                }
+            }
+            // Every read after the removal fails the same way, which is reported once.
+            Ok(AudioMessage::Error(err)) if !self.removed => {
+                self.removed = true;
+                events.push(StreamEvent::Removed(Some(*err)));
+            }
+            Err(err) if !self.removed && err.kind() == io::ErrorKind::UnexpectedEof => {
+                self.removed = true;
+                events.push(StreamEvent::Removed(None));
+            }
+            _ => (),
+        }
+        for event in events {
+            // The receiver may have been dropped, which is not an error of the stream.
+            let _ = self.event_sender.send(event);
+        }
+        result.map_err(Error::from)
+    }
+
     fn wait_request_data(&mut self) -> Result<(), Error> {
-        match self.controls.audio_sock_mut().read_audio_message()? {
+        match self.read_audio_message()? {
             AudioMessage::Success {
                 id: CRAS_AUDIO_MESSAGE_ID::AUDIO_MESSAGE_REQUEST_DATA,
                 ..
@@ -233,7 +644,7 @@ impl<'a, T: CrasStreamData<'a> + BufferCommit> CrasStream<'a, T> {
     }
 
     fn wait_data_ready(&mut self) -> Result<u32, Error> {
-        match self.controls.audio_sock_mut().read_audio_message()? {
+        match self.read_audio_message()? {
             AudioMessage::Success {
                 id: CRAS_AUDIO_MESSAGE_ID::AUDIO_MESSAGE_DATA_READY,
                 frames,
@@ -241,6 +652,344 @@ impl<'a, T: CrasStreamData<'a> + BufferCommit> CrasStream<'a, T> {
             _ => Err(Error::MessageTypeError),
         }
     }
+
+    // Waits for the next captured buffer.
+    //
+    // # Returns
+    // The offset and the length in bytes of the buffer in the samples area, and the
+    // frame size.
+    fn wait_captured_buffer(&mut self) -> Result<(usize, usize, usize), Error> {
+        let frames = self.wait_data_ready()?;
+        let header = self.controls.header();
+        let frame_size = header.get_frame_size();
+        let shm_frames = header.get_readable_frames()?;
+        let len = min(shm_frames, frames as usize) * frame_size;
+        let offset = header.get_read_buffer_offset()?;
+        Ok((offset, len, frame_size))
+    }
+}
+
+impl<'a> CrasStream<'a, CrasPlaybackData<'a>> {
+    /// Blocks until the server has consumed the first frames of this stream,
+    /// which marks the end of the warmup of the device.
+    ///
+    /// The server asks for data before it starts playing, so the first
+    /// `next_playback_buffer` returns before the stream is running. Call this
+    /// after committing the first buffer to get the time playback has truly
+    /// started, e.g. to synchronize with video. The shared memory is polled, so
+    /// the start is detected with a granularity of about a millisecond.
+    ///
+    /// # Errors
+    /// Returns `Error::StartTimeout` if no frame is consumed within `timeout`.
+    pub fn wait_until_running(&self, timeout: Duration) -> Result<(), Error> {
+        const POLL_INTERVAL: Duration = Duration::from_millis(1);
+        let start = Instant::now();
+        while !self.controls.header().has_read_frames() {
+            if start.elapsed() >= timeout {
+                return Err(Error::StartTimeout(timeout));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        Ok(())
+    }
+
+    /// Waits for the server to request data, then commits `frames` frames which
+    /// the caller already wrote to the samples area at `offset` bytes.
+    ///
+    /// This is the zero-copy counterpart of `next_playback_buffer` for streams
+    /// created with `CrasClient::new_playback_stream_with_shm`, where the samples
+    /// area is shared memory of the caller. Each buffer can be placed anywhere in
+    /// the area, as long as it does not overlap the buffer the server is reading.
+    ///
+    /// # Errors
+    /// Returns `Error::IoError` if the buffer at `offset` is outside the samples
+    /// area, overlaps the other buffer, or is shorter than `frames`.
+    pub fn commit_frames_at(&mut self, offset: usize, frames: usize) -> Result<(), Error> {
+        self.wait_request_data()?;
+        let header = self.controls.header_mut();
+        header.set_write_buffer_offset(offset)?;
+        header.commit_written_frames(frames as u32)?;
+        self.controls.audio_sock_mut().data_ready(frames as u32)?;
+        Ok(())
+    }
+}
+
+impl<'a> CrasStream<'a, CrasCaptureData<'a>> {
+    /// Discards the captured frames that are buffered in the shared memory but
+    /// not read yet.
+    ///
+    /// This only affects the frames already delivered to the client side buffers.
+    /// Frames still in flight in the server or the hardware are delivered later.
+    /// If the server already announced a buffer, the announcement is read and
+    /// answered with the discarded frames, so the next `next_capture_buffer` or
+    /// `frames` waits for the next buffer the server captures, instead of
+    /// returning an empty buffer.
+    ///
+    /// # Returns
+    /// The number of discarded frames.
+    ///
+    /// # Errors
+    /// Returns error if the shared memory header is invalid, or if reading or
+    /// answering the announcement of the server failed.
+    pub fn flush_capture(&mut self) -> Result<usize, Error> {
+        let mut announced = false;
+        while self.controls.audio_sock_mut().has_message()? {
+            self.wait_data_ready()?;
+            announced = true;
+        }
+        let discarded = self.controls.header_mut().discard_readable_frames()?;
+        if announced {
+            self.controls
+                .audio_sock_mut()
+                .capture_ready(discarded as u32)?;
+        }
+        Ok(discarded)
+    }
+
+    /// Returns an iterator over the captured buffers of this stream, each copied
+    /// into an owned `Vec<u8>` and committed as read.
+    ///
+    /// Each call to `next` blocks until the server delivers the next buffer. The
+    /// iterator never ends while the stream captures, so break out of the loop to
+    /// stop, which drops the iterator and leaves the stream usable. After an error,
+    /// e.g. when the server removes the stream, the iterator ends.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # fn capture(mut stream: libcras::CrasStream<libcras::CrasCaptureData>) {
+    /// for (i, block) in stream.frames().enumerate() {
+    ///     let block = block.expect("capture failed");
+    ///     println!("{} bytes", block.len());
+    ///     if i == 100 {
+    ///         break;
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub fn frames(&mut self) -> CaptureFrames<'_, 'a> {
+        CaptureFrames {
+            stream: self,
+            done: false,
+        }
+    }
+
+    // Waits for the next captured buffer, copies it and commits it as read.
+    fn read_captured_buffer(&mut self) -> Result<Vec<u8>, Error> {
+        let (offset, len, frame_size) = self.wait_captured_buffer()?;
+        let buffer = self.audio_buffer.get_buffer()[offset..offset + len].to_vec();
+        self.controls.commit(len / frame_size);
+        Ok(buffer)
+    }
+}
+
+/// An iterator over the captured buffers of a stream, see `CrasStream::frames`.
+pub struct CaptureFrames<'s, 'a> {
+    stream: &'s mut CrasStream<'a, CrasCaptureData<'a>>,
+    done: bool,
+}
+
+impl<'s, 'a> Iterator for CaptureFrames<'s, 'a> {
+    type Item = Result<Vec<u8>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.stream.read_captured_buffer();
+        self.done = result.is_err();
+        Some(result)
+    }
+}
+
+impl CrasStream<'static, CrasCaptureData<'static>> {
+    /// Moves the stream to a new thread which passes each captured buffer to
+    /// `callback`, with the time the first frame of the buffer was recorded.
+    ///
+    /// The stream and `callback` are owned by the thread and dropped together when
+    /// the returned handle is stopped, so the callback never runs after the stream
+    /// is disconnected.
+    ///
+    /// # Errors
+    /// Returns error if the eventfd waking the thread can not be created or the
+    /// thread can not be spawned.
+    pub fn start_capture_callback<F>(mut self, mut callback: F) -> io::Result<CaptureCallbackHandle>
+    where
+        F: FnMut(&[u8], Duration) + Send + 'static,
+    {
+        // Safe because the flags are valid and the result is checked.
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because `fd` is a new eventfd which is owned by nothing else.
+        let wake = Arc::new(unsafe { OwnedFd::from_raw_fd(fd) });
+        let thread_wake = Arc::clone(&wake);
+        let thread = thread::Builder::new()
+            .name(format!("cras_capture_{:x}", self.stream_id))
+            .spawn(move || {
+                while self.controls.audio_sock_mut().wait_readable(thread_wake.as_raw_fd())? {
+                    let (offset, len, frame_size) = self.wait_captured_buffer()?;
+                    let timestamp = self.controls.header().get_timestamp();
+                    callback(&self.audio_buffer.get_buffer()[offset..offset + len], timestamp);
+                    self.controls.commit(len / frame_size);
+                }
+                Ok(())
+            })?;
+        Ok(CaptureCallbackHandle {
+            wake,
+            thread: Some(thread),
+        })
+    }
+
+    /// Splits the stream into two readers which each receive a copy of every
+    /// captured buffer, for instance to record and monitor the same microphone
+    /// without connecting a second stream.
+    ///
+    /// The stream is serviced by a thread as with `start_capture_callback`, which
+    /// must keep up with the server. So a reader never slows down the other one:
+    /// each reader queues up to `capacity` buffers, and buffers captured while the
+    /// queue of a reader is full are dropped for that reader only, and counted by
+    /// `CaptureTee::dropped_buffers`.
+    ///
+    /// # Errors
+    /// Returns error if the thread can not be spawned.
+    pub fn tee(
+        self,
+        capacity: usize,
+    ) -> io::Result<(CaptureCallbackHandle, CaptureTee, CaptureTee)> {
+        let (first, first_sink) = CaptureTee::new(capacity);
+        let (second, second_sink) = CaptureTee::new(capacity);
+        let handle = self.start_capture_callback(move |buf, timestamp| {
+            first_sink.send(buf, timestamp);
+            second_sink.send(buf, timestamp);
+        })?;
+        Ok((handle, first, second))
+    }
+}
+
+/// A capture stream serviced by a thread, returned by
+/// `CrasStream::start_capture_callback`.
+///
+/// Dropping the handle stops the thread like `stop`, ignoring its result.
+pub struct CaptureCallbackHandle {
+    // An eventfd which wakes the thread to stop, also while it waits for a buffer.
+    wake: Arc<OwnedFd>,
+    thread: Option<JoinHandle<Result<(), Error>>>,
+}
+
+impl CaptureCallbackHandle {
+    /// Stops invoking the callback and joins the thread, which disconnects the
+    /// stream.
+    ///
+    /// The stop request wakes the thread while it waits for a buffer, so this does
+    /// not wait for the server, even if it never sends a buffer, e.g. for a
+    /// trigger-only stream which is not triggered. It only waits for the callback
+    /// to return if it is running.
+    ///
+    /// # Errors
+    /// Returns the error which ended the thread before the stop request, e.g. if
+    /// the server removed the stream, or if waking the thread failed.
+    pub fn stop(mut self) -> Result<(), Error> {
+        self.join()
+    }
+
+    fn join(&mut self) -> Result<(), Error> {
+        if self.thread.is_none() {
+            return Ok(());
+        }
+        let one: u64 = 1;
+        // Safe because `one` is valid for reads of the 8 bytes an eventfd write takes.
+        let ret = unsafe {
+            libc::write(
+                self.wake.as_raw_fd(),
+                &one as *const u64 as *const libc::c_void,
+                mem::size_of::<u64>(),
+            )
+        };
+        // The thread would never be woken, so it is not joined.
+        if ret < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(panic)) => std::panic::resume_unwind(panic),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for CaptureCallbackHandle {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            return;
+        }
+        if let Err(e) = self.join() {
+            error!("CaptureCallbackHandle::Drop error: {}", e);
+        }
+    }
+}
+
+/// A captured buffer and the time its first frame was recorded.
+pub type CapturedBuffer = (Vec<u8>, Duration);
+
+// The sending side of a `CaptureTee`, owned by the capture thread.
+struct CaptureTeeSink {
+    sender: SyncSender<CapturedBuffer>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl CaptureTeeSink {
+    fn send(&self, buf: &[u8], timestamp: Duration) {
+        // A disconnected reader no longer wants buffers, so only a full queue is
+        // counted as dropped.
+        if let Err(TrySendError::Full(_)) = self.sender.try_send((buf.to_vec(), timestamp)) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// One of the two readers of a capture stream split by `CrasStream::tee`.
+///
+/// The readers are disconnected when the `CaptureCallbackHandle` returned with
+/// them is stopped, the queued buffers can still be received.
+pub struct CaptureTee {
+    receiver: Receiver<CapturedBuffer>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl CaptureTee {
+    fn new(capacity: usize) -> (Self, CaptureTeeSink) {
+        let (sender, receiver) = sync_channel(capacity);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let sink = CaptureTeeSink {
+            sender,
+            dropped: Arc::clone(&dropped),
+        };
+        (Self { receiver, dropped }, sink)
+    }
+
+    /// Blocks until the next buffer is captured.
+    ///
+    /// # Returns
+    /// The buffer, or `None` if the capture thread has stopped.
+    pub fn recv(&self) -> Option<CapturedBuffer> {
+        self.receiver.recv().ok()
+    }
+
+    /// Waits for the next buffer for at most `timeout`.
+    ///
+    /// # Errors
+    /// Returns `RecvTimeoutError::Timeout` if no buffer was captured in time, and
+    /// `RecvTimeoutError::Disconnected` if the capture thread has stopped.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<CapturedBuffer, RecvTimeoutError> {
+        self.receiver.recv_timeout(timeout)
+    }
+
+    /// Gets the number of buffers dropped because this reader was too slow.
+    pub fn dropped_buffers(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
 }
 
 impl<'a, T: CrasStreamData<'a> + BufferCommit> Drop for CrasStream<'a, T> {
@@ -269,15 +1018,135 @@ impl<'a, T: CrasStreamData<'a> + BufferCommit> PlaybackBufferStream for CrasStre
 
 impl<'a, T: CrasStreamData<'a> + BufferCommit> CaptureBufferStream for CrasStream<'a, T> {
     fn next_capture_buffer<'b, 's: 'b>(&'b mut self) -> Result<CaptureBuffer<'b>, BoxError> {
-        // Wait for data ready message
-        let frames = self.wait_data_ready()?;
-        let header = self.controls.header_mut();
-        let frame_size = header.get_frame_size();
-        let shm_frames = header.get_readable_frames()?;
-        let len = min(shm_frames, frames as usize) * frame_size;
-        let offset = header.get_read_buffer_offset()?;
+        let (offset, len, frame_size) = self.wait_captured_buffer()?;
         let buf = &mut self.audio_buffer.get_buffer()[offset..offset + len];
 
         CaptureBuffer::new(frame_size, buf, &mut self.controls).map_err(Box::from)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io::Write;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+
+    use cras_sys::gen::cras_audio_shm_header;
+    use libchromeos::sys::unix::SharedMemory;
+
+    const BLOCK_SIZE: u32 = 4;
+    const FRAME_SIZE: usize = 4;
+
+    fn create_shm(size: usize) -> File {
+        SharedMemory::new(&CString::new("cras").unwrap(), size as u64)
+            .expect("failed to create shm")
+            .into()
+    }
+
+    // Creates a capture stream, with the server ends of its server socket and of its
+    // audio socket.
+    fn capture_stream() -> (
+        CrasStream<'static, CrasCaptureData<'static>>,
+        UnixStream,
+        UnixStream,
+    ) {
+        let mut fds = [0; 2];
+        // Safe because `fds` is valid for writes of two fds.
+        let ret =
+            unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr()) };
+        assert_eq!(ret, 0, "socketpair failed: {}", io::Error::last_os_error());
+        // Safe because `socketpair` returned two fds which are owned by nothing else.
+        let (server_socket, server_peer) =
+            unsafe { (OwnedFd::from_raw_fd(fds[0]), UnixStream::from_raw_fd(fds[1])) };
+        let (audio_sock, audio_peer) = UnixStream::pair().unwrap();
+
+        // The first buffer is captured, as the server does before it sends
+        // `AUDIO_MESSAGE_DATA_READY`.
+        let used_size = BLOCK_SIZE * FRAME_SIZE as u32;
+        // Safe because the header only has integer fields, for which zero is valid.
+        let mut header: cras_audio_shm_header = unsafe { mem::zeroed() };
+        header.config.used_size = used_size;
+        header.config.frame_bytes = FRAME_SIZE as u32;
+        header.buffer_offset = [0, used_size];
+        header.write_offset = [used_size, 0];
+        header.write_buf_idx = 1;
+        let mut header_shm = create_shm(mem::size_of::<cras_audio_shm_header>());
+        // Safe because `header` is a plain struct of the given size.
+        header_shm
+            .write_all(unsafe {
+                std::slice::from_raw_parts(
+                    &header as *const cras_audio_shm_header as *const u8,
+                    mem::size_of::<cras_audio_shm_header>(),
+                )
+            })
+            .unwrap();
+        let samples_len = 2 * used_size as usize;
+        let samples_shm = create_shm(samples_len);
+        // Safe because the fds are new shared memory areas of the given sizes.
+        let (header_fd, samples_fd) = unsafe {
+            (
+                CrasAudioShmHeaderFd::new(header_shm.into_raw_fd()),
+                CrasShmFd::new(samples_shm.into_raw_fd(), samples_len),
+            )
+        };
+        let stream = CrasStream::try_new(
+            0,
+            CrasServerSocket::from_fd(server_socket).unwrap(),
+            BLOCK_SIZE,
+            CRAS_STREAM_DIRECTION::CRAS_STREAM_INPUT,
+            48000,
+            2,
+            snd_pcm_format_t::SND_PCM_FORMAT_S16_LE,
+            AudioSocket::new(audio_sock),
+            header_fd,
+            samples_fd,
+        )
+        .unwrap();
+        (stream, server_peer, audio_peer)
+    }
+
+    #[test]
+    fn capture_callback_stops_without_data() {
+        let (stream, _server_peer, _audio_peer) = capture_stream();
+        let handle = stream
+            .start_capture_callback(|_, _| panic!("the server sent no buffer"))
+            .unwrap();
+        // The server never sends a buffer, so the thread waits on the audio socket.
+        thread::sleep(Duration::from_millis(10));
+        let (sender, receiver) = channel();
+        thread::spawn(move || sender.send(handle.stop().is_ok()).unwrap());
+        let stopped = receiver
+            .recv_timeout(Duration::from_secs(5))
+            .expect("stopping the capture thread hung");
+        assert!(stopped);
+    }
+
+    #[test]
+    fn read_after_flush_capture() {
+        let (mut stream, _server_peer, audio_peer) = capture_stream();
+        let server_audio = AudioSocket::new(audio_peer);
+        server_audio.data_ready(BLOCK_SIZE).unwrap();
+        assert_eq!(stream.flush_capture().unwrap(), BLOCK_SIZE as usize);
+        // The announced buffer is answered as read.
+        match server_audio.read_audio_message().unwrap() {
+            AudioMessage::Success { id, frames } => {
+                assert_eq!(id, CRAS_AUDIO_MESSAGE_ID::AUDIO_MESSAGE_DATA_CAPTURED);
+                assert_eq!(frames, BLOCK_SIZE);
+            }
+            AudioMessage::Error(err) => panic!("unexpected error message {}", err),
+        }
+
+        // The next read gets the next buffer captured by the server.
+        stream
+            .controls
+            .header_mut()
+            .commit_written_frames(BLOCK_SIZE)
+            .unwrap();
+        server_audio.data_ready(BLOCK_SIZE).unwrap();
+        let block = stream.frames().next().unwrap().unwrap();
+        assert_eq!(block.len(), BLOCK_SIZE as usize * FRAME_SIZE);
+    }
+}
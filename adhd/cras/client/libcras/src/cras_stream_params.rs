@@ -0,0 +1,218 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+use std::time::Duration;
+
+use audio_streams::{SampleFormat, StreamEffect};
+use cras_sys::gen::{
+    CRAS_INPUT_STREAM_FLAG, CRAS_STREAM_EFFECT as CrasStreamEffect, CRAS_STREAM_TYPE,
+};
+
+/// What a stream is used for, which selects the CRAS stream type it is connected
+/// with.
+///
+/// The server uses the stream type for its policies, e.g. the effects applied on
+/// the stream and the logging of stream usage. The mapping is:
+///
+/// | Purpose         | Stream type                            |
+/// |-----------------|----------------------------------------|
+/// | `Media`         | `CRAS_STREAM_TYPE_MULTIMEDIA`          |
+/// | `Notification`  | `CRAS_STREAM_TYPE_DEFAULT`             |
+/// | `Communication` | `CRAS_STREAM_TYPE_VOICE_COMMUNICATION` |
+/// | `Alarm`         | `CRAS_STREAM_TYPE_DEFAULT`             |
+/// | `Accessibility` | `CRAS_STREAM_TYPE_ACCESSIBILITY`       |
+///
+/// CRAS has no notification or alarm stream type, so those use the default type.
+/// The server has no volume per stream type either: all streams on a device
+/// follow the system volume, and a per-purpose level has to be set with the
+/// volume of each stream.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StreamPurpose {
+    Media,
+    Notification,
+    Communication,
+    Alarm,
+    Accessibility,
+}
+
+impl StreamPurpose {
+    /// Gets the CRAS stream type for the purpose.
+    pub fn stream_type(self) -> CRAS_STREAM_TYPE {
+        match self {
+            StreamPurpose::Media => CRAS_STREAM_TYPE::CRAS_STREAM_TYPE_MULTIMEDIA,
+            StreamPurpose::Notification | StreamPurpose::Alarm => {
+                CRAS_STREAM_TYPE::CRAS_STREAM_TYPE_DEFAULT
+            }
+            StreamPurpose::Communication => CRAS_STREAM_TYPE::CRAS_STREAM_TYPE_VOICE_COMMUNICATION,
+            StreamPurpose::Accessibility => CRAS_STREAM_TYPE::CRAS_STREAM_TYPE_ACCESSIBILITY,
+        }
+    }
+}
+
+/// Parameters used to connect a CRAS client stream.
+///
+/// `StreamParams::new` takes the required format of the stream, the optional
+/// parameters are set by the builder style functions.
+///
+/// The connect message has no resampler quality hint. If `frame_rate` differs
+/// from the rate of the device, the server resamples the stream with a fixed
+/// speex quality level, the same for every stream. A client which needs a
+/// different tradeoff should resample itself and open the stream at the rate of
+/// the device.
+///
+/// The number of buffers is not a parameter either. The shared memory of every
+/// stream holds `CRAS_NUM_SHM_BUFFERS`, two, buffers of `buffer_size` frames, so
+/// streams are always double buffered. A stream which needs more headroom against
+/// underruns should use a larger `buffer_size`, which adds latency the same way a
+/// third buffer would.
+///
+/// # Example
+///
+/// ```
+/// use audio_streams::SampleFormat;
+/// use libcras::StreamParams;
+///
+/// let params = StreamParams::new(2, SampleFormat::S16LE, 48000, 480)
+///     .device_index(1)
+///     .trigger_only(true);
+/// ```
+#[derive(Debug, Clone)]
+pub struct StreamParams {
+    pub(crate) device_index: Option<u32>,
+    pub(crate) num_channels: usize,
+    pub(crate) format: SampleFormat,
+    pub(crate) frame_rate: u32,
+    pub(crate) buffer_size: usize,
+    pub(crate) effects: CrasStreamEffect,
+    pub(crate) flags: u32,
+    pub(crate) initial_volume: Option<u8>,
+    pub(crate) client_shm_size: Option<u64>,
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) stream_type: Option<CRAS_STREAM_TYPE>,
+}
+
+impl StreamParams {
+    /// Creates `StreamParams` of a stream which follows the default device and
+    /// has no effects.
+    ///
+    /// # Arguments
+    ///
+    /// * `num_channels` - The count of audio channels for the stream.
+    /// * `format` - The format to use for stream audio samples.
+    /// * `frame_rate` - The sample rate of the stream.
+    /// * `buffer_size` - The transfer size granularity in frames.
+    pub fn new(
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+    ) -> Self {
+        Self {
+            device_index: None,
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size,
+            effects: CrasStreamEffect::empty(),
+            flags: 0,
+            initial_volume: None,
+            client_shm_size: None,
+            connect_timeout: None,
+            stream_type: None,
+        }
+    }
+
+    /// Pins the stream to the device at `device_index`.
+    ///
+    /// Pinning does not make the device exclusive to the stream. CRAS has no
+    /// exclusive or hog mode; the streams of all clients on a device are mixed,
+    /// and no connect flag asks the server to refuse other streams.
+    pub fn device_index(mut self, device_index: u32) -> Self {
+        self.device_index = Some(device_index);
+        self
+    }
+
+    /// Sets `buffer_size` to the frames of `latency` at `frame_rate`, rounded up and
+    /// at least one frame.
+    ///
+    /// The connect message has no latency, only the buffer size, from which the
+    /// server wakes the stream every `buffer_size` frames; the buffered latency of
+    /// the stream is about one buffer. E.g. 20 ms at 48000 is 960 frames. This
+    /// replaces the `buffer_size` given to `new`.
+    pub fn target_latency(mut self, latency: Duration) -> Self {
+        let frames = (latency.as_nanos() * u128::from(self.frame_rate) + 999_999_999)
+            / 1_000_000_000;
+        self.buffer_size = (frames as usize).max(1);
+        self
+    }
+
+    /// Sets the effects to apply to the stream.
+    pub fn effects(mut self, effects: &[StreamEffect]) -> Self {
+        self.effects = effects.iter().collect();
+        self
+    }
+
+    /// Sets the volume of the stream in percent, clamped to 0..=100, before the
+    /// stream is returned to the caller.
+    ///
+    /// The volume is written to the shared memory of the stream before any buffer
+    /// is handed to the client, and the server only mixes samples the client has
+    /// committed, so every sample is played at this volume.
+    pub fn initial_volume(mut self, volume: u8) -> Self {
+        self.initial_volume = Some(volume.min(100));
+        self
+    }
+
+    /// Allocates the samples area of the stream in the client with `size` bytes,
+    /// instead of letting the server size it for two buffers of `buffer_size`.
+    ///
+    /// The area must hold at least two buffers of `buffer_size` frames. The server
+    /// has no upper limit for client allocated areas. The size of each callback is
+    /// still `buffer_size`.
+    pub fn client_shm_size(mut self, size: u64) -> Self {
+        self.client_shm_size = Some(size);
+        self
+    }
+
+    /// Fails connecting the stream with `Error::StreamConnectTimeout` if the server
+    /// does not reply within `timeout`. Without a timeout, connecting waits for the
+    /// reply as long as the server is connected.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Connects the stream with the stream type of `purpose`, instead of the type
+    /// set on the client with `CrasClient::set_stream_type`.
+    pub fn purpose(mut self, purpose: StreamPurpose) -> Self {
+        self.stream_type = Some(purpose.stream_type());
+        self
+    }
+
+    /// Makes a capture stream trigger-only. Ignored for playback streams.
+    ///
+    /// A trigger-only stream receives no data until its device triggers, which is
+    /// used with hotword devices that detect a phrase in hardware. The server then
+    /// delivers a single callback of `buffer_size` frames and never wakes the stream
+    /// again, so the stream should be dropped after `StreamEvent::Triggered`. Audio
+    /// before the trigger is kept by the device, not by CRAS, so the server retains
+    /// at most the samples buffer of the stream.
+    ///
+    /// If the server is configured to pause hotword streams at suspend, the stream
+    /// is moved to an empty device during suspend and rearmed at resume, which is
+    /// not visible to the client.
+    pub fn trigger_only(mut self, trigger_only: bool) -> Self {
+        let flag = CRAS_INPUT_STREAM_FLAG::TRIGGER_ONLY as u32;
+        if trigger_only {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+        self
+    }
+
+    /// Whether the stream is trigger-only.
+    pub fn is_trigger_only(&self) -> bool {
+        self.flags & CRAS_INPUT_STREAM_FLAG::TRIGGER_ONLY as u32 != 0
+    }
+}
@@ -0,0 +1,121 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+use libchromeos::sys::info;
+
+use crate::{CrasCaptureData, CrasClient, CrasNodeType, CrasStream, Error, Result, StreamParams};
+
+/// A capture stream pinned to a device, which is reconnected to a device of a
+/// fallback node type when its device is removed.
+///
+/// The server removes the streams pinned to a device when the device is removed,
+/// e.g. when a USB microphone is unplugged, and notifies `SystemEvent::NodesChanged`.
+/// `check_device` should be called on each `NodesChanged` from
+/// `CrasClient::next_system_event`, or regularly. When the device is gone, a new
+/// stream with the same `StreamParams` is pinned to the input node of the
+/// fallback type, the active one if any, otherwise the most recently plugged
+/// one. The new stream has a new stream id, and the buffers captured between
+/// the removal and the reconnection are lost. The stream is not moved back when
+/// the device returns.
+///
+/// # Example
+///
+/// ```
+/// use libcras::{CrasClient, CrasNodeType, FallbackCapture, StreamParams, SystemEvent};
+///
+/// # async fn record(
+/// #     client: &mut CrasClient<'_>,
+/// #     params: StreamParams,
+/// #     ex: &dyn audio_streams::AudioStreamsExecutor,
+/// # ) -> Result<(), libcras::Error> {
+/// let mut capture = FallbackCapture::new(client, 3, params, CrasNodeType::CRAS_NODE_TYPE_MIC)?;
+/// loop {
+///     if client.next_system_event(ex).await? == SystemEvent::NodesChanged {
+///         capture.check_device(client)?;
+///     }
+/// }
+/// # }
+/// ```
+pub struct FallbackCapture<'b> {
+    params: StreamParams,
+    stream: CrasStream<'b, CrasCaptureData<'b>>,
+    device_index: u32,
+    fallback_node_type: CrasNodeType,
+}
+
+impl<'b> FallbackCapture<'b> {
+    /// Connects a capture stream with `params` pinned to the device at
+    /// `device_index` through `client`.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The client to connect the stream, and later its replacement.
+    /// * `device_index` - The device to pin the stream to first.
+    /// * `params` - The parameters of the stream. Its device index is replaced.
+    /// * `fallback_node_type` - The type of the input node to reconnect to.
+    ///
+    /// # Errors
+    ///
+    /// If connecting the stream failed, see `CrasClient::new_cras_capture_stream`.
+    pub fn new(
+        client: &mut CrasClient,
+        device_index: u32,
+        params: StreamParams,
+        fallback_node_type: CrasNodeType,
+    ) -> Result<Self> {
+        let params = params.device_index(device_index);
+        let stream = client.new_cras_capture_stream(&params)?;
+        Ok(Self {
+            params,
+            stream,
+            device_index,
+            fallback_node_type,
+        })
+    }
+
+    /// Gets the current stream. It changes after a fallback.
+    pub fn stream(&mut self) -> &mut CrasStream<'b, CrasCaptureData<'b>> {
+        &mut self.stream
+    }
+
+    /// Gets the index of the device the current stream is pinned to.
+    pub fn device_index(&self) -> u32 {
+        self.device_index
+    }
+
+    /// Checks whether the device of the stream is still present, and reconnects
+    /// the stream to a device of the fallback node type if it is not.
+    ///
+    /// # Returns
+    ///
+    /// Whether the stream was reconnected.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::NodeTypeNotFound` - If the device is gone and no input node has
+    ///   the fallback type. The current stream is kept then, and the next check
+    ///   tries again.
+    /// * If connecting the new stream failed.
+    pub fn check_device(&mut self, client: &mut CrasClient) -> Result<bool> {
+        if client
+            .input_nodes()
+            .any(|node| node.iodev_index == self.device_index)
+        {
+            return Ok(false);
+        }
+
+        let node = client
+            .preferred_input_node(self.fallback_node_type)
+            .ok_or(Error::NodeTypeNotFound(self.fallback_node_type))?;
+        let params = self.params.clone().device_index(node.iodev_index);
+        let stream = client.new_cras_capture_stream(&params)?;
+        info!(
+            "Device {} of capture stream removed, falling back to device {}",
+            self.device_index, node.iodev_index
+        );
+        self.stream = stream;
+        self.params = params;
+        self.device_index = node.iodev_index;
+        Ok(true)
+    }
+}
@@ -121,12 +121,22 @@
 // This should be removed when that bug is fixed.
 #![warn(unaligned_references)]
 
-use std::io;
+use std::cmp::Reverse;
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::ffi::CString;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, Write as _};
 use std::mem;
 use std::os::unix::{
-    io::{AsRawFd, RawFd},
+    io::{AsRawFd, OwnedFd, RawFd},
     net::UnixStream,
 };
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::{error, fmt};
 
 use async_trait::async_trait;
@@ -141,40 +151,78 @@ use audio_streams::{
 use cras_sys::gen::*;
 pub use cras_sys::gen::{
     CRAS_CLIENT_TYPE as CrasClientType, CRAS_NODE_TYPE as CrasNodeType,
-    CRAS_SCREEN_ROTATION as CrasScreenRotation, CRAS_STREAM_EFFECT as CrasStreamEffect,
-    CRAS_STREAM_TYPE as CrasStreamType,
+    CRAS_SCREEN_ROTATION as CrasScreenRotation, CRAS_STREAM_DIRECTION as CrasStreamDirection,
+    CRAS_STREAM_EFFECT as CrasStreamEffect, CRAS_STREAM_TYPE as CrasStreamType,
 };
 pub use cras_sys::{
-    deserialize_cras_client_type, AudioDebugInfo, CrasIodevInfo, CrasIodevNodeId, CrasIonodeInfo,
-    Error as CrasSysError,
+    deserialize_cras_client_type, AudioDebugInfo, AudioStreamDebugInfo, CrasIodevInfo,
+    CrasIodevNodeId, CrasIonodeInfo, Error as CrasSysError,
 };
+use futures::{future, future::Either, pin_mut};
 
 use libchromeos::deprecated::{PollContext, PollToken};
+use libchromeos::sys::{info, warn};
 
 mod async_;
 mod audio_socket;
 use crate::audio_socket::AudioSocket;
+mod auto_grow_playback;
+pub use crate::auto_grow_playback::AutoGrowPlayback;
+mod buffered_playback;
+pub use crate::buffered_playback::BufferedPlayback;
+mod cras_observer;
+pub use crate::cras_observer::CrasObserver;
 mod cras_server_socket;
 use crate::cras_server_socket::CrasServerSocket;
 pub use crate::cras_server_socket::CrasSocketType;
 mod cras_shm;
-use crate::cras_shm::CrasServerState;
+use crate::cras_shm::{CrasAudioShmHeaderFd, CrasServerState, CrasShmFd};
+pub use crate::cras_shm::{FeatureFlags, Topology, VolumeState};
 pub mod cras_shm_stream;
 use crate::cras_shm_stream::CrasShmStream;
-mod cras_stream;
-use crate::cras_stream::{CrasCaptureData, CrasPlaybackData, CrasStream, CrasStreamData};
+pub mod cras_stream;
+use crate::cras_stream::CrasStreamData;
+pub use crate::cras_stream::{
+    CallbackStats, CaptureCallbackHandle, CaptureFrames, CaptureTee, CapturedBuffer,
+    CrasCaptureData, CrasPlaybackData, CrasStream, FormatNegotiation, StreamEvent, StreamFormat,
+    StreamStats,
+};
+mod cras_stream_params;
+pub use crate::cras_stream_params::{StreamParams, StreamPurpose};
 mod cras_client_message;
 use crate::cras_client_message::*;
+pub use crate::cras_client_message::SystemEvent;
+mod fallback_capture;
+pub use crate::fallback_capture::FallbackCapture;
+mod peak_meter;
+pub use crate::peak_meter::PeakMeter;
 
 #[derive(Debug)]
 pub enum Error {
     CrasClientMessageError(cras_client_message::Error),
     CrasStreamError(cras_stream::Error),
     CrasSysError(cras_sys::Error),
+    CrasSocketConnectError(CrasSocketType, io::Error),
+    CaptureMuteLocked(bool),
+    CaptureNotPermitted,
+    ClientShmTooSmall(u64, u64),
+    DspEffectUnsupported(CrasStreamEffect),
     InvalidCrasSocket,
+    InvalidFrameRate(u32),
+    InvalidNumChannels(usize),
+    InvalidCaptureGain(i32),
     IoError(io::Error),
+    NoActiveNode(CrasStreamDirection),
+    NodeNotFound(CrasIodevNodeId),
+    NodeTypeNotFound(CrasNodeType),
+    ServerDisconnected,
+    ServerNotRunning(CrasSocketType, Duration),
+    StreamConnectFailed(i32),
+    StreamConnectRejected,
+    StreamConnectTimeout(Duration),
     SysUtilError(libchromeos::sys::Error),
     MessageTypeError,
+    TimedOut,
     UnexpectedExit,
 }
 
@@ -186,10 +234,80 @@ impl fmt::Display for Error {
             Error::CrasClientMessageError(ref err) => err.fmt(f),
             Error::CrasStreamError(ref err) => err.fmt(f),
             Error::CrasSysError(ref err) => err.fmt(f),
+            Error::CrasSocketConnectError(socket_type, ref err) => write!(
+                f,
+                "Failed to connect to {:?} cras socket ({}): {}",
+                socket_type,
+                socket_type.sock_path(),
+                err
+            ),
+            Error::CaptureMuteLocked(muted) => write!(
+                f,
+                "System capture mute is locked to {}",
+                if *muted { "muted" } else { "unmuted" }
+            ),
+            Error::CaptureNotPermitted => write!(f, "Capture is not enabled for the client"),
+            Error::ClientShmTooSmall(size, min_size) => write!(
+                f,
+                "Client shm size {} is smaller than the minimum {}",
+                size, min_size
+            ),
+            Error::DspEffectUnsupported(effects) => write!(
+                f,
+                "Running effects {:#x} on DSP is not supported",
+                effects.0
+            ),
             Error::InvalidCrasSocket => write!(f, "Invalid cras socket name"),
+            Error::InvalidFrameRate(rate) => write!(
+                f,
+                "Frame rate {} is not within {}..={}",
+                rate,
+                MIN_FRAME_RATE,
+                MAX_FRAME_RATE
+            ),
+            Error::InvalidNumChannels(num_channels) => write!(
+                f,
+                "Channel count {} is not within 1..={}",
+                num_channels,
+                CRAS_CHANNEL::CRAS_CH_MAX as usize
+            ),
+            Error::InvalidCaptureGain(gain) => write!(
+                f,
+                "Capture gain {} is not within {}..={}",
+                gain, MIN_CAPTURE_GAIN, MAX_CAPTURE_GAIN
+            ),
             Error::IoError(ref err) => err.fmt(f),
+            Error::NoActiveNode(direction) => write!(f, "No {:?} node is active", direction),
+            Error::NodeNotFound(node_id) => write!(
+                f,
+                "Node {}:{} does not exist",
+                node_id.iodev_index, node_id.ionode_index
+            ),
+            Error::NodeTypeNotFound(node_type) => {
+                write!(f, "No input node of type {:?} exists", node_type)
+            }
+            Error::ServerDisconnected => write!(f, "The CRAS server closed the connection"),
+            Error::ServerNotRunning(socket_type, max_wait) => write!(
+                f,
+                "The CRAS server did not come up on the {:?} socket ({}) within {:?}",
+                socket_type,
+                socket_type.sock_path(),
+                max_wait
+            ),
+            Error::StreamConnectFailed(err) => write!(
+                f,
+                "The server failed to connect the stream: {}",
+                io::Error::from_raw_os_error(-err)
+            ),
+            Error::StreamConnectRejected => {
+                write!(f, "The server rejected the parameters of the stream")
+            }
+            Error::StreamConnectTimeout(timeout) => {
+                write!(f, "The server did not connect the stream within {:?}", timeout)
+            }
             Error::SysUtilError(ref err) => err.fmt(f),
             Error::MessageTypeError => write!(f, "Message type error"),
+            Error::TimedOut => write!(f, "Timed out waiting for the server"),
             Error::UnexpectedExit => write!(f, "Unexpected exit"),
         }
     }
@@ -197,9 +315,23 @@ impl fmt::Display for Error {
 
 type Result<T> = std::result::Result<T, Error>;
 
+// The shared memory areas and the format of a connected stream.
+type StreamConnectedReply = (CrasAudioShmHeaderFd, CrasShmFd, cras_audio_format_packed);
+
+// The range of stream frame rates accepted by the server.
+const MIN_FRAME_RATE: u32 = 4000;
+const MAX_FRAME_RATE: u32 = 192000;
+
+// The range of the capture gain of an input node.
+const MIN_CAPTURE_GAIN: i32 = 0;
+const MAX_CAPTURE_GAIN: i32 = 100;
+
 impl From<io::Error> for Error {
     fn from(io_err: io::Error) -> Self {
-        Error::IoError(io_err)
+        match io_err.kind() {
+            io::ErrorKind::BrokenPipe => Error::ServerDisconnected,
+            _ => Error::IoError(io_err),
+        }
     }
 }
 
@@ -215,22 +347,325 @@ impl From<cras_stream::Error> for Error {
     }
 }
 
+impl From<cras_sys::Error> for Error {
+    fn from(err: cras_sys::Error) -> Self {
+        Error::CrasSysError(err)
+    }
+}
+
 impl From<cras_client_message::Error> for Error {
     fn from(err: cras_client_message::Error) -> Self {
         Error::CrasClientMessageError(err)
     }
 }
 
+/// Computes the buffer size in frames whose duration at `frame_rate` is at least `target`.
+///
+/// The buffer size is the transfer size granularity of a stream, so it bounds the
+/// latency the stream adds. A smaller buffer lowers the latency but requires more
+/// frequent wakeups, which increases the risk of underruns. The returned size is at
+/// least one frame. `CrasClient::default_output_buffer_size` reports the size the
+/// server uses by default, which is a safe upper reference.
+///
+/// ```
+/// use std::time::Duration;
+/// use libcras::buffer_size_for_latency;
+///
+/// assert_eq!(buffer_size_for_latency(Duration::from_millis(10), 48000), 480);
+/// assert_eq!(buffer_size_for_latency(Duration::from_micros(10), 44100), 1);
+/// assert_eq!(buffer_size_for_latency(Duration::from_millis(0), 44100), 1);
+/// ```
+pub fn buffer_size_for_latency(target: Duration, frame_rate: u32) -> usize {
+    let frames = (target.as_nanos() * frame_rate as u128 + 999_999_999) / 1_000_000_000;
+    frames.max(1) as usize
+}
+
+/// The mode to run the audio effects of a capture stream with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EffectMode {
+    /// Runs the effects on CPU.
+    Cpu,
+    /// Runs the effects on DSP. Fails if the system does not support running
+    /// the requested effects on DSP.
+    Dsp,
+    /// Runs the effects on DSP if the system supports it, otherwise on CPU.
+    Auto,
+}
+
+// Whether connecting the server socket failed with `err` because the server is
+// not running: the socket does not exist yet, or is left over from a server
+// which exited.
+fn server_not_running(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused)
+}
+
+// Sorts `nodes` with the active node first, then the plugged nodes from the most
+// recently plugged, then the unplugged nodes. Ties keep the server order.
+fn sort_nodes_by_priority(nodes: &mut [CrasIonodeInfo]) {
+    nodes.sort_by_key(|node| {
+        (
+            Reverse(node.active),
+            Reverse(node.plugged),
+            Reverse((node.plugged_time.tv_sec, node.plugged_time.tv_nsec)),
+        )
+    });
+}
+
+// Whether the jack of `node` is plugged, or `None` if the node has no jack.
+fn jack_plugged(node: &CrasIonodeInfo) -> Option<bool> {
+    use CrasNodeType::*;
+    match node.node_type {
+        CRAS_NODE_TYPE_HEADPHONE | CRAS_NODE_TYPE_LINEOUT | CRAS_NODE_TYPE_HDMI => {
+            Some(node.plugged)
+        }
+        // Internal mics are typed "INTERNAL_MIC", "FRONT_MIC" and so on.
+        CRAS_NODE_TYPE_MIC if node.type_name == "MIC" => Some(node.plugged),
+        _ => None,
+    }
+}
+
+/// The point of the output path a loopback capture stream records from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopbackSource {
+    /// The mix of all playback streams, before the output DSP pipeline.
+    PostMixPreDsp,
+    /// The output after the DSP pipeline, as sent to the device.
+    PostDsp,
+    /// Like `PostDsp`, but delayed by the latency of the output device, so it is
+    /// aligned with what is actually played.
+    PostDspDelayed,
+}
+
+impl LoopbackSource {
+    /// The type of the input node of the loopback device of this source.
+    pub fn node_type(self) -> CrasNodeType {
+        match self {
+            LoopbackSource::PostMixPreDsp => CrasNodeType::CRAS_NODE_TYPE_POST_MIX_PRE_DSP,
+            LoopbackSource::PostDsp => CrasNodeType::CRAS_NODE_TYPE_POST_DSP,
+            LoopbackSource::PostDspDelayed => CrasNodeType::CRAS_NODE_TYPE_POST_DSP_DELAYED,
+        }
+    }
+}
+
+/// Whether the Bluetooth super resolution (SR-BT) path applies to the active
+/// input node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SrBtAvailability {
+    /// The active input is a Bluetooth HFP mic and the "sr-bt-dlc" package is
+    /// installed.
+    Ready,
+    /// The active input is a Bluetooth HFP mic but the "sr-bt-dlc" package is
+    /// not installed.
+    DlcMissing,
+    /// The active input is not a Bluetooth HFP mic.
+    NotApplicable,
+}
+
+/// A client attached to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// The id the server assigned to the client.
+    pub id: u32,
+    /// The process id of the client.
+    pub pid: i32,
+    /// The user id of the client.
+    pub uid: u32,
+    /// The group id of the client.
+    pub gid: u32,
+    /// The type of the client, reported with its streams. `None` if the client
+    /// has no stream.
+    pub client_type: Option<CrasClientType>,
+    /// The number of streams of the client.
+    pub num_streams: usize,
+}
+
+#[derive(PollToken)]
+enum ServerToken {
+    ServerMsg,
+}
+
+/// A set of system audio settings applied by `CrasClient::apply_audio_state`.
+///
+/// Only the settings which are `Some` are changed.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AudioState {
+    /// The system output volume in 0..=100.
+    pub volume: Option<u32>,
+    /// The system output mute.
+    pub mute: Option<bool>,
+    /// An input node and its capture gain in 0..=100. CRAS has no system capture
+    /// gain, the gain is set per input node.
+    pub capture_gain: Option<(CrasIodevNodeId, i32)>,
+    /// The system capture mute.
+    pub capture_mute: Option<bool>,
+}
+
+/// The audio effects supported by a node.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NodeEffects {
+    /// The effects streams of the node can request, including the DSP allowed
+    /// bits of the effects the node can run on DSP.
+    pub stream_effects: CrasStreamEffect,
+    /// Whether the system noise cancellation is available on the node.
+    pub noise_cancellation: bool,
+}
+
+/// The operations a `CrasClient` is permitted to do, as returned by
+/// `CrasClient::capabilities`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Whether the client can create playback streams.
+    pub playback: bool,
+    /// Whether the client can create capture streams, see
+    /// `CrasClient::enable_cras_capture`.
+    pub capture: bool,
+    /// Whether the client can set the system volume and mute.
+    pub volume_control: bool,
+    /// Whether the client can set the system capture mute, which is not the case
+    /// while it is locked.
+    pub capture_mute_control: bool,
+    /// Whether the client can set the attributes of nodes, e.g. their volume.
+    pub node_control: bool,
+}
+
+/// The shared memory mapped by a `CrasClient`, as returned by
+/// `CrasClient::shm_usage`.
+///
+/// Only the areas visible to the client are counted. The server maps the same
+/// areas, so they are not allocated twice, but the buffers of devices,
+/// resamplers and DSP pipelines are internal to the server and not included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShmUsage {
+    /// The size of the server state, mapped read-only by every client.
+    pub server_state: usize,
+    /// The shared memory of each stream of the client which is still attached.
+    pub streams: Vec<StreamShmUsage>,
+}
+
+impl ShmUsage {
+    /// The total size of the shared memory mapped by the client.
+    pub fn total(&self) -> usize {
+        self.server_state
+            + self
+                .streams
+                .iter()
+                .map(|s| s.header + s.samples)
+                .sum::<usize>()
+    }
+}
+
+/// The shared memory of a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamShmUsage {
+    /// The server stream id of the stream.
+    pub stream_id: u32,
+    /// The size of the shared memory header of the stream.
+    pub header: usize,
+    /// The size of the samples area of the stream. It is allocated by the client
+    /// for streams with a client provided shared memory.
+    pub samples: usize,
+}
+
+/// The outcome of `CrasClient::apply_node_volumes`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeVolumeReport {
+    /// The nodes whose volume was set, in the order of the profile.
+    pub applied: Vec<CrasIodevNodeId>,
+    /// The nodes of the profile which are not present output nodes.
+    pub skipped: Vec<CrasIodevNodeId>,
+}
+
+/// A stream connected again by `CrasClient::reconnect_and_restore`, with the
+/// server stream id of the stream it replaces.
+pub enum RestoredStream<'b> {
+    Playback(u32, CrasStream<'b, CrasPlaybackData<'b>>),
+    Capture(u32, CrasStream<'b, CrasCaptureData<'b>>),
+}
+
+// The parameters a stream was created with, to restore it after a reconnect.
+struct TrackedStream {
+    stream_id: u32,
+    direction: CRAS_STREAM_DIRECTION,
+    params: StreamParams,
+    // Dangling once the stream is dropped.
+    alive: Weak<()>,
+}
+
+/// The frequency of the usual test tone for `CrasClient::play_test_tone`, A4.
+pub const TEST_TONE_FREQUENCY: f32 = 440.0;
+
+// The format of the test tone stream.
+const TEST_TONE_FRAME_RATE: u32 = 48000;
+const TEST_TONE_BUFFER_SIZE: usize = 480;
+// The amplitude of the test tone, half of full scale.
+const TEST_TONE_AMPLITUDE: f32 = 16384.0;
+
+/// The minimum interval between the volume steps of a fade.
+pub const FADE_STEP_INTERVAL: Duration = Duration::from_millis(10);
+
+// Returns the volume of `step` of `num_steps` of a fade from `from` to `target`.
+fn fade_step_volume(from: u32, target: u32, step: u32, num_steps: u32) -> u32 {
+    let delta = i64::from(target.min(100)) - i64::from(from);
+    (i64::from(from) + delta * i64::from(step) / i64::from(num_steps)) as u32
+}
+
+// A task running on a thread, such as a volume fade, which is canceled on drop.
+struct BackgroundTask {
+    cancel: Option<Sender<()>>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl BackgroundTask {
+    // Cancels the task, and returns whether it was still waiting to run.
+    fn cancel(mut self) -> bool {
+        // A task which has finished has dropped its receiver.
+        self.cancel
+            .take()
+            .map_or(false, |cancel| cancel.send(()).is_ok())
+    }
+}
+
+impl Drop for BackgroundTask {
+    fn drop(&mut self) {
+        // Disconnects the cancel channel to stop the fade thread.
+        self.cancel.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
 /// A CRAS server client, which implements StreamSource and ShmStreamSource.
 /// It can create audio streams connecting to CRAS server.
+///
+/// The server has no notion of sessions: every connection is an independent
+/// client with its own client id, whether the clients are in the same process
+/// or not. The system volume and mutes are shared by all clients, and the only
+/// per-client state is the streams of the client, with their own volume.
 pub struct CrasClient<'a> {
     server_socket: CrasServerSocket,
+    poll_ctx: PollContext<ServerToken>,
     server_state: CrasServerState<'a>,
     client_id: u32,
     next_stream_id: u32,
     cras_capture: bool,
     client_type: CRAS_CLIENT_TYPE,
     stream_type: CRAS_STREAM_TYPE,
+    socket_type: CrasSocketType,
+    proto_version: u32,
+    system_events_registered: bool,
+    // System events received while waiting for the reply of a request.
+    pending_system_events: VecDeque<SystemEvent>,
+    volume_fade: Option<BackgroundTask>,
+    // The pending unmute of `set_system_mute_for`, and the mute state it restores.
+    auto_unmute: Option<(BackgroundTask, bool)>,
+    // The shared memory of the streams created by the client.
+    stream_shm: Vec<StreamShmUsage>,
+    // The jack states of the nodes when system events were last checked.
+    jack_states: Vec<(CrasIodevNodeId, bool)>,
+    restore_streams: bool,
+    // The streams to restore after a reconnect, if `restore_streams` is set.
+    tracked_streams: Vec<TrackedStream>,
 }
 
 impl<'a> CrasClient<'a> {
@@ -252,42 +687,314 @@ impl<'a> CrasClient<'a> {
     ///
     /// # Errors
     ///
-    /// Returns error if error occurs while handling server message or message
-    /// type is incorrect.
+    /// * `Error::CrasSocketConnectError` - If the socket of `socket_type` cannot be
+    ///   connected, e.g. it is not supported by the running server. Carries the
+    ///   attempted socket type and the underlying `io::Error`.
+    /// * Other errors if error occurs while handling server message or message
+    ///   type is incorrect.
     pub fn with_type(socket_type: CrasSocketType) -> Result<Self> {
         // Create a connection to the server.
-        let mut server_socket = CrasServerSocket::with_type(socket_type)?;
+        let server_socket = CrasServerSocket::with_type(socket_type)
+            .map_err(|err| Error::CrasSocketConnectError(socket_type, err))?;
+        Self::from_server_socket(server_socket, socket_type)
+    }
+
+    /// Blocks creating a `CrasClient` with a given `CrasSocketType`, retrying every
+    /// `retry_interval` until the server is up or `max_wait` elapses.
+    ///
+    /// This is meant for services which may start before the server. The server
+    /// is considered not running while its socket does not exist or refuses the
+    /// connection, other errors are not retried.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::ServerNotRunning` - If the server did not come up within `max_wait`.
+    /// * Other errors of `with_type`, which are returned without retrying.
+    pub fn connect_blocking(
+        socket_type: CrasSocketType,
+        retry_interval: Duration,
+        max_wait: Duration,
+    ) -> Result<Self> {
+        let start = Instant::now();
+        loop {
+            match Self::with_type(socket_type) {
+                Err(Error::CrasSocketConnectError(_, err)) if server_not_running(&err) => {
+                    let elapsed = start.elapsed();
+                    if elapsed >= max_wait {
+                        return Err(Error::ServerNotRunning(socket_type, max_wait));
+                    }
+                    thread::sleep(retry_interval.min(max_wait - elapsed));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Records `seconds` of audio with a capture stream of `params`, on a new
+    /// client of `client_type` which is disconnected afterwards.
+    ///
+    /// # Returns
+    ///
+    /// The samples of exactly `seconds * frame_rate` frames, in the format and
+    /// channel layout of `params`.
+    ///
+    /// # Errors
+    ///
+    /// * If connecting the client or the stream failed, see `new` and
+    ///   `new_cras_capture_stream`.
+    /// * If reading the stream failed, e.g. when the server removed it.
+    pub fn record_seconds(
+        client_type: CRAS_CLIENT_TYPE,
+        seconds: u32,
+        params: &StreamParams,
+    ) -> Result<Vec<u8>> {
+        let mut client = Self::new()?;
+        client.set_client_type(client_type);
+        client.enable_cras_capture();
+        let mut stream = client.new_cras_capture_stream(params)?;
+
+        let frame_size = params.num_channels * params.format.sample_bytes();
+        let len = seconds as usize * params.frame_rate as usize * frame_size;
+        let mut samples = Vec::with_capacity(len);
+        let mut blocks = stream.frames();
+        while samples.len() < len {
+            match blocks.next() {
+                Some(block) => samples.extend_from_slice(&block?),
+                None => break,
+            }
+        }
+        samples.truncate(len);
+        Ok(samples)
+    }
+
+    /// Creates a `CrasClient` over an already connected server socket.
+    ///
+    /// This is useful when the process cannot open the server socket by path,
+    /// e.g. in a sandbox where the connected socket is passed in as an fd.
+    /// `fd` must be a `SOCK_SEQPACKET` socket connected to the CRAS server socket
+    /// of `socket_type`; server messages are read by message boundaries, so a
+    /// `SOCK_STREAM` socket is rejected.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - The connected server socket.
+    /// * `socket_type` - The type of the server socket `fd` is connected to.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if `fd` is not a `SOCK_SEQPACKET` socket, if error occurs
+    /// while handling server message or message type is incorrect.
+    pub fn from_fd(fd: OwnedFd, socket_type: CrasSocketType) -> Result<Self> {
+        Self::from_server_socket(CrasServerSocket::from_fd(fd)?, socket_type)
+    }
+
+    // Runs the connection handshake over a connected `server_socket`.
+    fn from_server_socket(
+        mut server_socket: CrasServerSocket,
+        socket_type: CrasSocketType,
+    ) -> Result<Self> {
+        // The poll context is kept for the lifetime of the client, so waiting for
+        // a message costs a single epoll_wait. `server_socket` is never replaced,
+        // and the clones used by async waits share its open file, so the
+        // registration stays valid.
+        let poll_ctx: PollContext<ServerToken> = PollContext::new()
+            .and_then(|pc| pc.add(&server_socket, ServerToken::ServerMsg).and(Ok(pc)))?;
         // Gets client ID and server state fd from server
         if let ServerResult::Connected(client_id, server_state_fd) =
-            CrasClient::wait_for_message(&mut server_socket)?
+            CrasClient::wait_for_message(&poll_ctx, &mut server_socket, None)?
         {
             Ok(Self {
                 server_socket,
+                poll_ctx,
                 server_state: CrasServerState::try_new(server_state_fd)?,
                 client_id,
                 next_stream_id: 0,
                 cras_capture: false,
                 client_type: CRAS_CLIENT_TYPE::CRAS_CLIENT_TYPE_UNKNOWN,
                 stream_type: CRAS_STREAM_TYPE::CRAS_STREAM_TYPE_DEFAULT,
+                socket_type,
+                proto_version: CRAS_PROTO_VER,
+                system_events_registered: false,
+                pending_system_events: VecDeque::new(),
+                volume_fade: None,
+                auto_unmute: None,
+                stream_shm: Vec::new(),
+                jack_states: Vec::new(),
+                restore_streams: false,
+                tracked_streams: Vec::new(),
             })
         } else {
             Err(Error::MessageTypeError)
         }
     }
 
+    /// Tries to create a `CrasClient` with `preferred` socket type, and falls back
+    /// to `CrasSocketType::Legacy` if the preferred socket cannot be connected.
+    ///
+    /// The socket type which is finally used is logged and returned, so callers
+    /// can check whether the fallback happened.
+    ///
+    /// # Returns
+    ///
+    /// * (`CrasClient`, `CrasSocketType`) - The client and the socket type it is connected with.
+    ///
+    /// # Errors
+    ///
+    /// * If connecting to the preferred socket failed for a reason other than
+    ///   connecting the socket itself.
+    /// * If connecting to the `Legacy` socket also failed.
+    pub fn new_with_fallback(preferred: CrasSocketType) -> Result<(Self, CrasSocketType)> {
+        match Self::with_type(preferred) {
+            Ok(client) => {
+                info!("Connected to {:?} cras socket", preferred);
+                Ok((client, preferred))
+            }
+            Err(Error::CrasSocketConnectError(socket_type, err))
+                if socket_type != CrasSocketType::Legacy =>
+            {
+                warn!(
+                    "Failed to connect to {:?} cras socket: {}, falling back to Legacy",
+                    socket_type, err
+                );
+                let client = Self::with_type(CrasSocketType::Legacy)?;
+                info!("Connected to {:?} cras socket", CrasSocketType::Legacy);
+                Ok((client, CrasSocketType::Legacy))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Gets a version string of the server to log, e.g. in bug reports.
+    ///
+    /// The server reports no build or release version, neither in its state nor in
+    /// a message, and does not send its protocol version either. The only version
+    /// it exposes is the layout version of the server state, which the client
+    /// checked to be `CRAS_SERVER_STATE_VERSION` when connecting, so that is the
+    /// version returned, along with the protocol version this client sends.
+    pub fn server_version_string(&self) -> String {
+        format!(
+            "server state version {}, client protocol version {}",
+            CRAS_SERVER_STATE_VERSION, self.proto_version
+        )
+    }
+
+    /// Gets the type of the server socket this client is connected with.
+    pub fn socket_type(&self) -> CrasSocketType {
+        self.socket_type
+    }
+
     /// Enables capturing audio through CRAS server.
     pub fn enable_cras_capture(&mut self) {
         self.cras_capture = true;
     }
 
+    /// Gets the operations the client is permitted to do.
+    ///
+    /// The server reports no permissions, and does not acknowledge most control
+    /// messages, so a denied request is silently ignored rather than reported. The
+    /// capabilities are derived from the state the client knows: capture is
+    /// permitted once enabled by `enable_cras_capture`, and the capture mute while
+    /// it is not locked. The server socket of the client accepts the other
+    /// operations, so they are always reported as permitted, though the server may
+    /// still ignore a request by policy.
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            playback: true,
+            capture: self.cras_capture,
+            volume_control: true,
+            capture_mute_control: !self.capture_mute_locked(),
+            node_control: true,
+        }
+    }
+
+    /// Makes the client remember the parameters of the streams it creates from
+    /// now on, so that `reconnect_and_restore` can create them again.
+    ///
+    /// Streams created with a caller provided shared memory are not remembered.
+    pub fn enable_stream_restore(&mut self) {
+        self.restore_streams = true;
+    }
+
+    /// Connects the client to the server again, e.g. after the server restarted.
+    ///
+    /// The new connection uses the same socket type, and keeps the settings of
+    /// the client: capture, client type, stream type, protocol version and stream
+    /// restore. The registrations for system events and a volume fade in progress
+    /// are not kept. The streams of the previous connection are not reconnected,
+    /// see `reconnect_and_restore`.
+    ///
+    /// # Errors
+    ///
+    /// * If connecting the new client failed, see `with_type`. The client keeps
+    ///   the previous connection then.
+    pub fn reconnect(&mut self) -> Result<()> {
+        let mut client = Self::with_type(self.socket_type)?;
+        client.cras_capture = self.cras_capture;
+        client.client_type = self.client_type;
+        client.stream_type = self.stream_type;
+        client.proto_version = self.proto_version;
+        client.restore_streams = self.restore_streams;
+        client.tracked_streams = mem::take(&mut self.tracked_streams);
+        *self = client;
+        Ok(())
+    }
+
+    /// Connects the client to the server again like `reconnect`, then creates
+    /// again the streams which were created since `enable_stream_restore` and
+    /// are not dropped yet.
+    ///
+    /// Each stream is created with the parameters it was created with, and gets
+    /// a new stream id. The audio buffered in the previous streams is lost, as is
+    /// their per-stream volume and mute. The previous streams should be dropped
+    /// and replaced by the returned ones.
+    ///
+    /// # Errors
+    ///
+    /// * If reconnecting failed, see `reconnect`.
+    /// * If creating a stream failed. The streams restored so far are dropped,
+    ///   and the streams not restored yet are forgotten.
+    pub fn reconnect_and_restore<'b>(&mut self) -> Result<Vec<RestoredStream<'b>>> {
+        self.reconnect()?;
+        let tracked = mem::take(&mut self.tracked_streams);
+        let mut restored = Vec::new();
+        for stream in tracked.iter().filter(|s| s.alive.upgrade().is_some()) {
+            restored.push(match stream.direction {
+                CRAS_STREAM_DIRECTION::CRAS_STREAM_OUTPUT => RestoredStream::Playback(
+                    stream.stream_id,
+                    self.create_stream(stream.direction, &stream.params)?,
+                ),
+                _ => RestoredStream::Capture(
+                    stream.stream_id,
+                    self.create_stream(stream.direction, &stream.params)?,
+                ),
+            });
+        }
+        Ok(restored)
+    }
+
     /// Set the type of this client to report to CRAS when connecting streams.
     pub fn set_client_type(&mut self, client_type: CRAS_CLIENT_TYPE) {
         self.client_type = client_type;
     }
 
+    /// Overrides the protocol version sent in connect stream messages.
+    ///
+    /// This is only for testing how the server handles clients built against an
+    /// older protocol. Do not use it in production, the messages are always built
+    /// with the current layout regardless of `version`.
+    pub fn set_proto_version_override(&mut self, version: u32) {
+        self.proto_version = version;
+    }
+
     /// Set the stream type to report to CRAS when connecting streams.
     ///
-    /// All streams created under this client will use this stream type.
+    /// All streams created under this client will use this stream type, unless
+    /// their `StreamParams` set a `StreamPurpose`.
+    ///
+    /// The server does not report a policy per stream type, and the crate assumes
+    /// none: streams of all types on a device are mixed together at the system
+    /// volume, and no type ducks or mutes another. The type is used by the server
+    /// to select stream policies such as audio effects, and for metrics.
     pub fn set_stream_type(&mut self, stream_type: CRAS_STREAM_TYPE) {
         self.stream_type = stream_type;
     }
@@ -295,118 +1002,1394 @@ impl<'a> CrasClient<'a> {
     /// Sets the system volume to `volume`.
     ///
     /// Send a message to the server to request setting the system volume
-    /// to `volume`. No response is returned from the server.
+    /// to `volume`. No response is returned from the server. A fade started by
+    /// `fade_system_volume` is canceled.
     ///
     /// # Errors
     ///
     /// If writing the message to the server socket failed.
     pub fn set_system_volume(&mut self, volume: u32) -> Result<()> {
-        let header = cras_server_message {
-            length: mem::size_of::<cras_set_system_volume>() as u32,
-            id: CRAS_SERVER_MESSAGE_ID::CRAS_SERVER_SET_SYSTEM_VOLUME,
-        };
-        let msg = cras_set_system_volume { header, volume };
-
-        self.server_socket.send_server_message_with_fds(&msg, &[])?;
+        self.volume_fade = None;
+        self.server_socket.set_system_volume(volume)?;
         Ok(())
     }
 
-    /// Sets the system mute status to `mute`.
+    /// Fades the system volume from its current value to `target` over `duration`.
     ///
-    /// Send a message to the server to request setting the system mute
-    /// to `mute`. No response is returned from the server.
+    /// The fade runs on a thread with a clone of the server socket, so this
+    /// returns without waiting. The volume is sent in steps of at least one volume
+    /// unit, spaced evenly over `duration` and at least `FADE_STEP_INTERVAL`
+    /// apart; the last step sets `target`. A new fade, `set_system_volume`, or
+    /// dropping the client cancels the fade in progress, leaving the volume of
+    /// the last step.
     ///
     /// # Errors
     ///
-    /// If writing the message to the server socket failed.
-    pub fn set_system_mute(&mut self, mute: bool) -> Result<()> {
-        let header = cras_server_message {
-            length: mem::size_of::<cras_set_system_mute>() as u32,
-            id: CRAS_SERVER_MESSAGE_ID::CRAS_SERVER_SET_SYSTEM_MUTE,
-        };
-        let msg = cras_set_system_mute {
-            header,
-            mute: mute as i32,
-        };
-
-        self.server_socket.send_server_message_with_fds(&msg, &[])?;
+    /// * If cloning the server socket or spawning the fade thread failed.
+    pub fn fade_system_volume(&mut self, target: u32, duration: Duration) -> Result<()> {
+        self.volume_fade = None;
+        let (from, num_steps, interval) = self.fade_plan(target, duration);
+        let socket = self.server_socket.try_clone()?;
+        let (cancel, cancel_rx) = channel::<()>();
+        let handle = thread::Builder::new()
+            .name("cras_volume_fade".to_owned())
+            .spawn(move || {
+                for step in 1..=num_steps {
+                    // Dropping `cancel` disconnects the channel and stops the fade.
+                    if cancel_rx.recv_timeout(interval) != Err(RecvTimeoutError::Timeout) {
+                        return;
+                    }
+                    let volume = fade_step_volume(from, target, step, num_steps);
+                    if let Err(e) = socket.set_system_volume(volume) {
+                        warn!("Failed to set volume {} of fade: {}", volume, e);
+                        return;
+                    }
+                }
+            })?;
+        self.volume_fade = Some(BackgroundTask {
+            cancel: Some(cancel),
+            handle: Some(handle),
+        });
         Ok(())
     }
 
-    /// Gets the system volume.
+    /// Fades the system volume from its current value to `target` over `duration`,
+    /// stepping on `ex`.
     ///
-    /// Read the current value for system volume from the server shared memory.
-    pub fn get_system_volume(&self) -> u32 {
-        self.server_state.get_system_volume()
+    /// The steps are the same as `fade_system_volume`. Dropping the returned future
+    /// cancels the fade, leaving the volume of the last step.
+    ///
+    /// # Errors
+    ///
+    /// * If writing a message to the server socket failed.
+    pub async fn async_fade_system_volume(
+        &mut self,
+        target: u32,
+        duration: Duration,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<()> {
+        self.volume_fade = None;
+        let (from, num_steps, interval) = self.fade_plan(target, duration);
+        for step in 1..=num_steps {
+            ex.delay(interval).await?;
+            self.server_socket
+                .set_system_volume(fade_step_volume(from, target, step, num_steps))?;
+        }
+        Ok(())
     }
 
-    /// Gets the system mute.
-    ///
-    /// Read the current value for system mute from the server shared memory.
-    pub fn get_system_mute(&self) -> bool {
-        self.server_state.get_system_mute()
+    // Returns the volume to fade from, the number of steps and the interval
+    // between them for a fade to `target` over `duration`.
+    fn fade_plan(&self, target: u32, duration: Duration) -> (u32, u32, Duration) {
+        let from = self.get_system_volume();
+        let target = target.min(100);
+        let max_steps = (duration.as_nanos() / FADE_STEP_INTERVAL.as_nanos()) as u32;
+        let num_steps = max_steps.min(from.max(target) - from.min(target)).max(1);
+        (from, num_steps, duration / num_steps)
     }
 
-    /// Gets a list of output devices
+    /// Sets the system volume to `volume` and blocks until the server notifies
+    /// applying it.
     ///
-    /// Read a list of the currently attached output devices from the server shared memory.
-    pub fn output_devices(&self) -> impl Iterator<Item = CrasIodevInfo> {
-        self.server_state.output_devices()
+    /// This is the blocking version of `async_set_system_volume_confirmed`. The
+    /// client is registered for system events first, like by `next_system_event`,
+    /// so the notification cannot be missed. Only a volume change to the requested
+    /// volume, received after the request, confirms it: the server notifies every
+    /// request, so changes from earlier requests or other clients may still be
+    /// queued. The other system events received meanwhile are kept for
+    /// `next_system_event`, and other messages are dropped.
+    ///
+    /// # Returns
+    ///
+    /// * `u32` - The system volume applied by the server, which clamps it to 100.
+    ///
+    /// # Errors
+    ///
+    /// * If registering for system events or writing the message failed.
+    /// * `Error::TimedOut` - If the volume change was not notified within `timeout`.
+    pub fn set_system_volume_confirmed(&mut self, volume: u32, timeout: Duration) -> Result<u32> {
+        let deadline = Instant::now() + timeout;
+        self.register_system_events()?;
+        self.set_system_volume(volume)?;
+        let expected = volume.min(100);
+        self.wait_for_system_event(deadline, |event| {
+            CrasClient::confirms_system_volume(event, expected)
+        })?;
+        Ok(expected)
     }
 
-    /// Gets a list of input devices
+    /// Sets the system volume to `volume` and waits on `ex` for the notification
+    /// of the server applying it.
     ///
-    /// Read a list of the currently attached input devices from the server shared memory.
-    pub fn input_devices(&self) -> impl Iterator<Item = CrasIodevInfo> {
-        self.server_state.input_devices()
+    /// This is the async version of `set_system_volume_confirmed`, and is
+    /// confirmed the same way. The system events queued for `next_system_event`
+    /// before the call are not checked, since they may predate the request.
+    ///
+    /// # Returns
+    ///
+    /// * `u32` - The system volume applied by the server, which clamps it to 100.
+    ///
+    /// # Errors
+    ///
+    /// * If registering for system events or writing the message failed.
+    /// * `Error::TimedOut` - If the volume change was not notified within `timeout`.
+    pub async fn async_set_system_volume_confirmed(
+        &mut self,
+        volume: u32,
+        timeout: Duration,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<u32> {
+        self.register_system_events()?;
+        self.set_system_volume(volume)?;
+        let expected = volume.min(100);
+        self.async_wait_for_system_event(timeout, ex, |event| {
+            CrasClient::confirms_system_volume(event, expected)
+        })
+        .await?;
+        Ok(expected)
     }
 
-    /// Gets a list of output nodes
-    ///
-    /// Read a list of the currently attached output nodes from the server shared memory.
-    pub fn output_nodes(&self) -> impl Iterator<Item = CrasIonodeInfo> {
-        self.server_state.output_nodes()
+    // Whether `event` notifies the system volume changing to `volume`.
+    fn confirms_system_volume(event: &SystemEvent, volume: u32) -> bool {
+        matches!(event, SystemEvent::OutputVolumeChanged(applied) if *applied == volume as i32)
     }
 
-    /// Gets a list of input nodes
+    /// Sets the system mute status to `mute`.
     ///
-    /// Read a list of the currently attached input nodes from the server shared memory.
-    pub fn input_nodes(&self) -> impl Iterator<Item = CrasIonodeInfo> {
-        self.server_state.input_nodes()
+    /// Send a message to the server to request setting the system mute
+    /// to `mute`. No response is returned from the server. A pending unmute of
+    /// `set_system_mute_for` is canceled.
+    ///
+    /// CRAS does not track mute per node, so the system mute applies to all
+    /// output nodes.
+    ///
+    /// # Errors
+    ///
+    /// If writing the message to the server socket failed.
+    pub fn set_system_mute(&mut self, mute: bool) -> Result<()> {
+        self.auto_unmute = None;
+        self.server_socket.set_system_mute(mute)?;
+        Ok(())
     }
 
-    /// Gets the server's audio debug info.
+    /// Mutes the system now, and restores the prior mute state after `duration`.
     ///
-    /// Sends a message to the server requesting an update of audio debug info,
-    /// waits for the response, and then reads the info from the server state.
+    /// The restore runs on a thread with a clone of the server socket, so this
+    /// returns without waiting. A new call replaces the pending restore, still
+    /// restoring the state before the first call; `set_system_mute` or dropping
+    /// the client cancels it, leaving the system muted.
     ///
     /// # Errors
     ///
-    /// * If sending the message to the server failed.
-    /// * If an unexpected response message is received.
-    pub fn get_audio_debug_info(&mut self) -> Result<AudioDebugInfo> {
-        let header = cras_server_message {
-            length: mem::size_of::<cras_dump_audio_thread>() as u32,
-            id: CRAS_SERVER_MESSAGE_ID::CRAS_SERVER_DUMP_AUDIO_THREAD,
+    /// * If writing the message to the server socket failed.
+    /// * If cloning the server socket or spawning the restore thread failed.
+    pub fn set_system_mute_for(&mut self, duration: Duration) -> Result<()> {
+        let restore = self.take_auto_unmute();
+        self.server_socket.set_system_mute(true)?;
+        let socket = self.server_socket.try_clone()?;
+        let (cancel, cancel_rx) = channel::<()>();
+        let handle = thread::Builder::new()
+            .name("cras_auto_unmute".to_owned())
+            .spawn(move || {
+                // Sending on or dropping `cancel` cancels the restore.
+                if cancel_rx.recv_timeout(duration) != Err(RecvTimeoutError::Timeout) {
+                    return;
+                }
+                if let Err(e) = socket.set_system_mute(restore) {
+                    warn!("Failed to restore system mute {}: {}", restore, e);
+                }
+            })?;
+        let task = BackgroundTask {
+            cancel: Some(cancel),
+            handle: Some(handle),
         };
-        let msg = cras_dump_audio_thread { header };
-
-        self.server_socket.send_server_message_with_fds(&msg, &[])?;
-
-        match CrasClient::wait_for_message(&mut self.server_socket)? {
-            ServerResult::DebugInfoReady => Ok(self
-                .server_state
-                .get_audio_debug_info()
-                .map_err(Error::CrasSysError)?),
-            _ => Err(Error::MessageTypeError),
-        }
+        self.auto_unmute = Some((task, restore));
+        Ok(())
     }
 
-    // Gets next server_stream_id from client and increment stream_id counter.
-    fn next_server_stream_id(&mut self) -> u32 {
-        let res = self.next_stream_id;
-        self.next_stream_id += 1;
-        self.server_stream_id(res)
+    /// Mutes the system now, and restores the prior mute state after `duration`,
+    /// waiting on `ex`.
+    ///
+    /// A pending restore of `set_system_mute_for` is replaced, as with a new call
+    /// to it. Dropping the returned future cancels the restore, leaving the system
+    /// muted.
+    ///
+    /// # Errors
+    ///
+    /// * If writing a message to the server socket failed.
+    pub async fn async_set_system_mute_for(
+        &mut self,
+        duration: Duration,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<()> {
+        let restore = self.take_auto_unmute();
+        self.server_socket.set_system_mute(true)?;
+        ex.delay(duration).await?;
+        self.server_socket.set_system_mute(restore)?;
+        Ok(())
+    }
+
+    // Cancels the pending unmute of `set_system_mute_for`, and returns the mute
+    // state to restore after a new temporary mute.
+    fn take_auto_unmute(&mut self) -> bool {
+        match self.auto_unmute.take() {
+            Some((task, restore)) if task.cancel() => restore,
+            _ => self.get_system_mute(),
+        }
+    }
+
+    /// Applies the settings of `state` which are `Some`.
+    ///
+    /// The protocol has no message setting several values, so each setting is sent
+    /// in its own message and the server applies and notifies them one by one;
+    /// other clients can observe the intermediate states. To avoid audible
+    /// intermediate states, the messages are sent in this order:
+    ///
+    /// 1. Muting the output or the capture.
+    /// 2. The output volume and the capture gain.
+    /// 3. Unmuting the output or the capture.
+    ///
+    /// All the settings are checked before the first message is sent, so a rejected
+    /// state changes nothing. A fade of the system volume in progress is cancelled.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::CaptureMuteLocked` - If `capture_mute` is set while the capture mute
+    ///   is locked.
+    /// * `Error::InvalidCaptureGain` - If the gain of `capture_gain` is not within
+    ///   0..=100.
+    /// * `Error::NodeNotFound` - If the node of `capture_gain` is not an input node.
+    /// * If writing a message to the server socket failed.
+    pub fn apply_audio_state(&mut self, state: &AudioState) -> Result<()> {
+        if state.capture_mute.is_some() && self.server_state.get_system_capture_mute_locked() {
+            return Err(Error::CaptureMuteLocked(
+                self.server_state.get_system_capture_mute(),
+            ));
+        }
+        if let Some((node_id, gain)) = state.capture_gain {
+            if !(MIN_CAPTURE_GAIN..=MAX_CAPTURE_GAIN).contains(&gain) {
+                return Err(Error::InvalidCaptureGain(gain));
+            }
+            if !self.server_state.has_input_node(node_id) {
+                return Err(Error::NodeNotFound(node_id));
+            }
+        }
+
+        if state.mute == Some(true) {
+            self.set_system_mute(true)?;
+        }
+        if state.capture_mute == Some(true) {
+            self.set_system_capture_mute(true)?;
+        }
+        if let Some(volume) = state.volume {
+            self.set_system_volume(volume)?;
+        }
+        if let Some((node_id, gain)) = state.capture_gain {
+            self.set_node_attr(node_id, ionode_attr::IONODE_ATTR_CAPTURE_GAIN, gain)?;
+        }
+        if state.mute == Some(false) {
+            self.set_system_mute(false)?;
+        }
+        if state.capture_mute == Some(false) {
+            self.set_system_capture_mute(false)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the volume of each output node of `profile`, in percent clamped to
+    /// 0..=100, e.g. to restore saved volumes.
+    ///
+    /// The nodes which are not present output nodes, e.g. an unplugged headset,
+    /// are skipped. The protocol has no message setting several volumes, so each
+    /// volume is sent in its own message and applied by the server one by one.
+    ///
+    /// # Returns
+    ///
+    /// The nodes of `profile` which were applied and skipped.
+    ///
+    /// # Errors
+    ///
+    /// * If writing a message to the server socket failed. The volumes of the nodes
+    ///   before the failed one are set.
+    pub fn apply_node_volumes(
+        &mut self,
+        profile: &[(CrasIodevNodeId, u8)],
+    ) -> Result<NodeVolumeReport> {
+        let mut report = NodeVolumeReport::default();
+        for &(node_id, volume) in profile {
+            match self.set_node_volume(node_id, volume) {
+                Ok(()) => report.applied.push(node_id),
+                Err(Error::NodeNotFound(_)) => report.skipped.push(node_id),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Sets the volume of the output node `node_id` to `volume` in percent, clamped
+    /// to 0..=100, without changing the system volume or the other nodes.
+    ///
+    /// The server has no message for the volume of a node alone, the volume is
+    /// sent as the volume attribute of the node. No response is returned from the
+    /// server, which notifies `SystemEvent::OutputNodeVolumeChanged`.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::NodeNotFound` - If `node_id` is not an attached output node.
+    /// * If writing the message to the server socket failed.
+    pub fn set_node_volume(&mut self, node_id: CrasIodevNodeId, volume: u8) -> Result<()> {
+        if !self.server_state.has_output_node(node_id) {
+            return Err(Error::NodeNotFound(node_id));
+        }
+        self.set_node_attr(
+            node_id,
+            ionode_attr::IONODE_ATTR_VOLUME,
+            i32::from(volume.min(100)),
+        )
+    }
+
+    /// Swaps the left and right channels of the output node `node_id` if `swap`,
+    /// e.g. for speakers which are wired reversed, or restores them otherwise.
+    ///
+    /// The server has no message for the swap alone, it is sent as the swap
+    /// attribute of the node, and applied by the device when it supports swapping.
+    /// No response is returned from the server. The current swap of a node is
+    /// `CrasIonodeInfo::left_right_swapped`.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::NodeNotFound` - If `node_id` is not an attached output node, which
+    ///   includes input nodes.
+    /// * If writing the message to the server socket failed.
+    pub fn set_node_left_right_swapped(
+        &mut self,
+        node_id: CrasIodevNodeId,
+        swap: bool,
+    ) -> Result<()> {
+        if !self.server_state.has_output_node(node_id) {
+            return Err(Error::NodeNotFound(node_id));
+        }
+        self.set_node_attr(
+            node_id,
+            ionode_attr::IONODE_ATTR_SWAP_LEFT_RIGHT,
+            i32::from(swap),
+        )
+    }
+
+    /// Selects the output node `node_id` as the active output node, e.g. to switch
+    /// between the speakers and the headphones.
+    ///
+    /// The streams following the default device are moved to the node, and the
+    /// other output nodes become inactive. No response is returned from the
+    /// server, which notifies `SystemEvent::ActiveNodeChanged`.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::NodeNotFound` - If `node_id` is not an attached output node.
+    /// * If writing the message to the server socket failed.
+    pub fn set_active_output_node(&mut self, node_id: CrasIodevNodeId) -> Result<()> {
+        if !self.server_state.has_output_node(node_id) {
+            return Err(Error::NodeNotFound(node_id));
+        }
+        self.server_socket
+            .select_node(CRAS_STREAM_DIRECTION::CRAS_STREAM_OUTPUT, node_id.into())?;
+        Ok(())
+    }
+
+    /// Selects the input node `node_id` as the active input node, like
+    /// `set_active_output_node`.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::NodeNotFound` - If `node_id` is not an attached input node.
+    /// * If writing the message to the server socket failed.
+    pub fn set_active_input_node(&mut self, node_id: CrasIodevNodeId) -> Result<()> {
+        if !self.server_state.has_input_node(node_id) {
+            return Err(Error::NodeNotFound(node_id));
+        }
+        self.server_socket
+            .select_node(CRAS_STREAM_DIRECTION::CRAS_STREAM_INPUT, node_id.into())?;
+        Ok(())
+    }
+
+    /// Adds the node `node_id` to the active nodes of `direction`, e.g. to play to
+    /// both the speakers and HDMI.
+    ///
+    /// Unlike `set_active_output_node`, the nodes which are already active stay
+    /// active. Adding a node which is already active leaves the active nodes
+    /// unchanged on the server, but the node must still be attached. No response
+    /// is returned from the server, which notifies `SystemEvent::ActiveNodeChanged`.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::NodeNotFound` - If `node_id` is not an attached node of `direction`.
+    /// * If writing the message to the server socket failed.
+    pub fn add_active_node(
+        &mut self,
+        direction: CRAS_STREAM_DIRECTION,
+        node_id: CrasIodevNodeId,
+    ) -> Result<()> {
+        if !self.has_node(direction, node_id) {
+            return Err(Error::NodeNotFound(node_id));
+        }
+        self.server_socket.add_active_node(direction, node_id.into())?;
+        Ok(())
+    }
+
+    /// Removes the node `node_id` from the active nodes of `direction`, undoing
+    /// `add_active_node`.
+    ///
+    /// No response is returned from the server, which notifies
+    /// `SystemEvent::ActiveNodeChanged`.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::NodeNotFound` - If `node_id` is not an attached node of `direction`.
+    /// * If writing the message to the server socket failed.
+    pub fn rm_active_node(
+        &mut self,
+        direction: CRAS_STREAM_DIRECTION,
+        node_id: CrasIodevNodeId,
+    ) -> Result<()> {
+        if !self.has_node(direction, node_id) {
+            return Err(Error::NodeNotFound(node_id));
+        }
+        self.server_socket.rm_active_node(direction, node_id.into())?;
+        Ok(())
+    }
+
+    // Checks whether `node_id` is an attached node of `direction`. Only output and
+    // input nodes are listed in the server state.
+    fn has_node(&self, direction: CRAS_STREAM_DIRECTION, node_id: CrasIodevNodeId) -> bool {
+        match direction {
+            CRAS_STREAM_DIRECTION::CRAS_STREAM_OUTPUT => self.server_state.has_output_node(node_id),
+            CRAS_STREAM_DIRECTION::CRAS_STREAM_INPUT => self.server_state.has_input_node(node_id),
+            _ => false,
+        }
+    }
+
+    // Sends a message setting the attribute `attr` of the node `node_id` to `value`.
+    // No response is returned from the server.
+    fn set_node_attr(
+        &mut self,
+        node_id: CrasIodevNodeId,
+        attr: ionode_attr,
+        value: i32,
+    ) -> Result<()> {
+        let header = cras_server_message {
+            length: mem::size_of::<cras_set_node_attr>() as u32,
+            id: CRAS_SERVER_MESSAGE_ID::CRAS_SERVER_SET_NODE_ATTR,
+        };
+        let msg = cras_set_node_attr {
+            header,
+            node_id: node_id.into(),
+            attr,
+            value,
+        };
+
+        self.server_socket.send_server_message_with_fds(&msg, &[])?;
+        Ok(())
+    }
+
+    /// Sets the system capture mute status to `mute`.
+    ///
+    /// Send a message to the server to request setting the system capture mute
+    /// to `mute`. No response is returned from the server.
+    ///
+    /// The capture mute can be locked, e.g. when it is enforced by a hardware
+    /// privacy switch or by policy, in which case the server would ignore the
+    /// request. The lock is checked before sending, so such a request is reported
+    /// as an error instead. Use `effective_capture_mute` to read the mute in effect.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::CaptureMuteLocked` - If the capture mute is locked, carrying the
+    ///   capture mute in effect.
+    /// * If writing the message to the server socket failed.
+    pub fn set_system_capture_mute(&mut self, mute: bool) -> Result<()> {
+        if self.server_state.get_system_capture_mute_locked() {
+            return Err(Error::CaptureMuteLocked(
+                self.server_state.get_system_capture_mute(),
+            ));
+        }
+
+        let header = cras_server_message {
+            length: mem::size_of::<cras_set_system_mute>() as u32,
+            id: CRAS_SERVER_MESSAGE_ID::CRAS_SERVER_SET_SYSTEM_CAPTURE_MUTE,
+        };
+        let msg = cras_set_system_mute {
+            header,
+            mute: mute as i32,
+        };
+
+        self.server_socket.send_server_message_with_fds(&msg, &[])?;
+        Ok(())
+    }
+
+    /// Sets the system capture mute to `mute` and blocks until the server notifies
+    /// applying it.
+    ///
+    /// The client is registered for system events first, and only a capture mute
+    /// change to `mute` received after the request confirms it, like in
+    /// `set_system_volume_confirmed`. The other system events received meanwhile
+    /// are kept for `next_system_event`, and other messages are dropped.
+    ///
+    /// Once the server applies the mute, it zeroes the input of every capture
+    /// stream it mixes from then on. The buffers it already wrote
+    /// to the shared memory of a stream, at most its two buffers, are not muted,
+    /// so a client which must not get any unmuted samples after this call should
+    /// drop the buffers pending on its streams.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::CaptureMuteLocked` - If the capture mute is locked.
+    /// * If registering for system events or writing the message failed.
+    /// * `Error::TimedOut` - If the mute change was not notified within `timeout`.
+    pub fn set_system_capture_mute_confirmed(
+        &mut self,
+        mute: bool,
+        timeout: Duration,
+    ) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        self.register_system_events()?;
+        self.set_system_capture_mute(mute)?;
+        self.wait_for_system_event(deadline, |event| {
+            matches!(event, SystemEvent::CaptureMuteChanged { muted, .. } if *muted == mute)
+        })?;
+        Ok(())
+    }
+
+    /// Gets the system capture mute in effect.
+    ///
+    /// Read the capture mute from the server shared memory. It reflects the
+    /// locked value if the capture mute is locked, regardless of the requests of
+    /// clients, e.g. a hardware privacy switch. To wait until a mute set by
+    /// `set_system_capture_mute` is in effect, see
+    /// `set_system_capture_mute_confirmed`.
+    pub fn effective_capture_mute(&self) -> bool {
+        self.server_state.get_system_capture_mute()
+    }
+
+    /// Gets whether the system capture mute is locked, so it cannot be changed by
+    /// `set_system_capture_mute`.
+    pub fn capture_mute_locked(&self) -> bool {
+        self.server_state.get_system_capture_mute_locked()
+    }
+
+    /// Sets the capture gain of the active input node to `gain`.
+    ///
+    /// CRAS has no system capture gain anymore: the server ignores the deprecated
+    /// `CRAS_SERVER_SET_SYSTEM_CAPTURE_GAIN`, and no longer updates the capture
+    /// gain in dBFS * 100 of the server state. The gain is an attribute of each
+    /// input node, in 0..=100, so it is set on the active input node instead,
+    /// which captures the streams following the default device. No response is
+    /// returned from the server.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::InvalidCaptureGain` - If `gain` is not within 0..=100.
+    /// * `Error::NoActiveNode` - If no input node is active.
+    /// * If writing the message to the server socket failed.
+    pub fn set_system_capture_gain(&mut self, gain: i32) -> Result<()> {
+        if !(MIN_CAPTURE_GAIN..=MAX_CAPTURE_GAIN).contains(&gain) {
+            return Err(Error::InvalidCaptureGain(gain));
+        }
+        let node = self
+            .input_nodes()
+            .find(|node| node.active)
+            .ok_or(Error::NoActiveNode(CrasStreamDirection::CRAS_STREAM_INPUT))?;
+        let node_id = CrasIodevNodeId {
+            iodev_index: node.iodev_index,
+            ionode_index: node.ionode_index,
+        };
+        self.set_node_attr(node_id, ionode_attr::IONODE_ATTR_CAPTURE_GAIN, gain)
+    }
+
+    /// Gets the capture gain of the active input node, see
+    /// `set_system_capture_gain`.
+    ///
+    /// Read the gain from the input nodes in the server shared memory.
+    ///
+    /// # Returns
+    ///
+    /// The gain in 0..=100, or `None` if no input node is active.
+    pub fn get_system_capture_gain(&self) -> Option<i32> {
+        self.input_nodes()
+            .find(|node| node.active)
+            .map(|node| node.capture_gain)
+    }
+
+    /// Gets the system volume.
+    ///
+    /// Read the current value for system volume from the server shared memory.
+    pub fn get_system_volume(&self) -> u32 {
+        self.server_state.get_system_volume()
+    }
+
+    /// Gets the system mute.
+    ///
+    /// Read the current value for system mute from the server shared memory.
+    pub fn get_system_mute(&self) -> bool {
+        self.server_state.get_system_mute()
+    }
+
+    /// Gets the system capture mute.
+    ///
+    /// Read the current value for system capture mute from the server shared
+    /// memory, like `get_system_mute`. This is the mute in effect, see
+    /// `effective_capture_mute`.
+    pub fn get_system_capture_mute(&self) -> bool {
+        self.server_state.get_system_capture_mute()
+    }
+
+    /// Gets the system volume state.
+    ///
+    /// Read the system volume, its approximate value in dB and the system mute from the server
+    /// shared memory in one consistent read, so the values cannot drift between
+    /// separate calls of `get_system_volume` and `get_system_mute`.
+    pub fn system_volume_state(&self) -> VolumeState {
+        self.server_state.get_system_volume_state()
+    }
+
+    /// Checks whether audio played to the active output node can be heard.
+    ///
+    /// Returns `false` if the system or the user mute is set, the system volume is
+    /// zero, or no active output node has a non-zero volume. CRAS has no per node
+    /// mute, so a node with zero volume is treated as muted.
+    pub fn output_audible(&self) -> bool {
+        let volume_state = self.system_volume_state();
+        if volume_state.muted || volume_state.scalar == 0 || self.server_state.get_user_mute() {
+            return false;
+        }
+        self.output_nodes()
+            .any(|node| node.active && node.volume > 0)
+    }
+
+    /// Checks whether the Bluetooth super resolution path applies to the active
+    /// input node.
+    ///
+    /// libcras does not talk to dlcservice, so the caller passes whether the
+    /// "sr-bt-dlc" package is installed, e.g. from `cras_dlc::dlc_state`. The
+    /// server may still keep SR-BT disabled on boards or feature flags which do
+    /// not support it.
+    ///
+    /// # Arguments
+    ///
+    /// * `dlc_installed` - Whether the "sr-bt-dlc" package is installed.
+    pub fn sr_bt_availability(&self, dlc_installed: bool) -> SrBtAvailability {
+        let is_hfp_mic = self.input_nodes().any(|node| {
+            node.active
+                && matches!(
+                    node.node_type,
+                    CrasNodeType::CRAS_NODE_TYPE_BLUETOOTH
+                        | CrasNodeType::CRAS_NODE_TYPE_BLUETOOTH_NB_MIC
+                )
+        });
+        match (is_hfp_mic, dlc_installed) {
+            (false, _) => SrBtAvailability::NotApplicable,
+            (true, false) => SrBtAvailability::DlcMissing,
+            (true, true) => SrBtAvailability::Ready,
+        }
+    }
+
+    /// Gets whether the jack of the node `node_id` is plugged.
+    ///
+    /// Only headphone, line out, HDMI and external mic nodes have a jack. Other
+    /// nodes are plugged as long as their device is present, which is reported by
+    /// `CrasIonodeInfo::plugged`. Jack changes are also reported by
+    /// `SystemEvent::JackPlugChanged`.
+    ///
+    /// # Returns
+    ///
+    /// * Whether the jack is plugged, or `None` if the node has no jack detection.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::NodeNotFound` - If no node has `node_id`.
+    pub fn node_jack_plugged(&self, node_id: CrasIodevNodeId) -> Result<Option<bool>> {
+        self.output_nodes()
+            .chain(self.input_nodes())
+            .find(|node| {
+                node.iodev_index == node_id.iodev_index && node.ionode_index == node_id.ionode_index
+            })
+            .map(|node| jack_plugged(&node))
+            .ok_or(Error::NodeNotFound(node_id))
+    }
+
+    // Gets the jack states of all the nodes with a jack.
+    fn jack_states(&self) -> Vec<(CrasIodevNodeId, bool)> {
+        self.output_nodes()
+            .chain(self.input_nodes())
+            .filter_map(|node| {
+                let node_id = CrasIodevNodeId {
+                    iodev_index: node.iodev_index,
+                    ionode_index: node.ionode_index,
+                };
+                jack_plugged(&node).map(|plugged| (node_id, plugged))
+            })
+            .collect()
+    }
+
+    // Queues a `SystemEvent::JackPlugChanged` after `event` for each jack changed
+    // since the last check, if `event` is a `SystemEvent::NodesChanged`.
+    fn queue_jack_changes(&mut self, event: SystemEvent) -> SystemEvent {
+        if event != SystemEvent::NodesChanged {
+            return event;
+        }
+        let jack_states = self.jack_states();
+        let old_states = mem::replace(&mut self.jack_states, jack_states);
+        let state_of = |states: &[(CrasIodevNodeId, bool)], node_id: CrasIodevNodeId| {
+            states.iter().find(|(id, _)| *id == node_id).map(|(_, plugged)| *plugged)
+        };
+        let mut changes: Vec<_> = self
+            .jack_states
+            .iter()
+            .filter(|&&(node_id, plugged)| {
+                state_of(&old_states, node_id).unwrap_or(false) != plugged
+            })
+            .copied()
+            .collect();
+        // A removed node is reported as unplugged.
+        changes.extend(
+            old_states
+                .iter()
+                .filter(|&&(node_id, plugged)| {
+                    plugged && state_of(&self.jack_states, node_id).is_none()
+                })
+                .map(|&(node_id, _)| (node_id, false)),
+        );
+        for (node_id, plugged) in changes.into_iter().rev() {
+            self.pending_system_events
+                .push_front(SystemEvent::JackPlugChanged { node_id, plugged });
+        }
+        event
+    }
+
+    /// Gets the audio effects supported by the node `node_id`.
+    ///
+    /// Stream effects are only applied to capture streams, so output nodes and
+    /// input nodes which bypass the audio processing, like hotword and loopback
+    /// nodes, support no effect. DSP effects are only offloaded on internal mics.
+    /// Noise cancellation is reported by the server per node, after checking its
+    /// provider, including the availability of the DLC for noise cancellation on
+    /// AP.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::NodeNotFound` - If no node has `node_id`.
+    pub fn node_supported_effects(&self, node_id: CrasIodevNodeId) -> Result<NodeEffects> {
+        let is_node = |node: &CrasIonodeInfo| {
+            node.iodev_index == node_id.iodev_index && node.ionode_index == node_id.ionode_index
+        };
+        let none = NodeEffects {
+            stream_effects: CrasStreamEffect::empty(),
+            noise_cancellation: false,
+        };
+        if self.output_nodes().any(|node| is_node(&node)) {
+            return Ok(none);
+        }
+        let node = self
+            .input_nodes()
+            .find(is_node)
+            .ok_or(Error::NodeNotFound(node_id))?;
+
+        use CrasNodeType::*;
+        let is_mic = match node.node_type {
+            CRAS_NODE_TYPE_MIC => true,
+            CRAS_NODE_TYPE_USB | CRAS_NODE_TYPE_BLUETOOTH | CRAS_NODE_TYPE_BLUETOOTH_NB_MIC => {
+                false
+            }
+            _ => return Ok(none),
+        };
+        let mut stream_effects = CrasStreamEffect::APM_ECHO_CANCELLATION
+            | CrasStreamEffect::APM_NOISE_SUPRESSION
+            | CrasStreamEffect::APM_GAIN_CONTROL
+            | CrasStreamEffect::APM_VOICE_DETECTION;
+        if is_mic {
+            let dsp_effects = [
+                (
+                    CrasStreamEffect::DSP_ECHO_CANCELLATION_ALLOWED,
+                    self.server_state.get_aec_on_dsp_supported(),
+                ),
+                (
+                    CrasStreamEffect::DSP_NOISE_SUPPRESSION_ALLOWED,
+                    self.server_state.get_ns_on_dsp_supported(),
+                ),
+                (
+                    CrasStreamEffect::DSP_GAIN_CONTROL_ALLOWED,
+                    self.server_state.get_agc_on_dsp_supported(),
+                ),
+            ];
+            for (allowed, supported) in dsp_effects {
+                if supported {
+                    stream_effects |= allowed;
+                }
+            }
+        }
+        Ok(NodeEffects {
+            stream_effects,
+            noise_cancellation: node.noise_cancellation_supported,
+        })
+    }
+
+    /// Checks whether the connection to the server is alive.
+    ///
+    /// Polls the server socket without side effects; pending server messages are
+    /// kept. `true` only means the socket is open and the server has not closed
+    /// its end. It does not mean the server is responsive; a hung server is
+    /// only detected by a request which times out.
+    pub fn is_alive(&self) -> bool {
+        self.server_socket.is_connected()
+    }
+
+    /// Waits until the connection to the server is lost.
+    ///
+    /// Resolves when the server closes its end of the socket or the socket fails,
+    /// e.g. when the server exits or restarts, not when the server is idle or hung;
+    /// see `is_alive`. Messages the server sends while waiting are read, system
+    /// events are kept for `next_system_event` and other messages are dropped.
+    ///
+    /// # Errors
+    ///
+    /// * If waiting on the server socket failed.
+    /// * If a message received while connected is invalid.
+    pub async fn wait_for_disconnect(&mut self, ex: &dyn AudioStreamsExecutor) -> Result<()> {
+        while self.server_socket.is_connected() {
+            ex.wait_fd_readable(self.server_socket.try_clone()?.as_raw_fd())
+                .await?;
+            if !self.server_socket.is_connected() {
+                break;
+            }
+            match ServerResult::handle_server_message(&mut self.server_socket) {
+                Ok(ServerResult::SystemEvent(event)) => self.pending_system_events.push_back(event),
+                Ok(_) => (),
+                Err(e) if self.server_socket.is_connected() => return Err(e.into()),
+                Err(_) => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets the server's feature flags.
+    ///
+    /// The server does not send its feature flags over the protocol. This is a
+    /// best-effort subset of the features enabled or supported by the server,
+    /// read from the server shared memory.
+    pub fn server_feature_flags(&self) -> FeatureFlags {
+        self.server_state.get_feature_flags()
+    }
+
+    /// Gets the default output buffer size
+    ///
+    /// Read the default output buffer size in frames from the server shared memory.
+    pub fn default_output_buffer_size(&self) -> usize {
+        self.server_state.get_default_output_buffer_size().max(0) as usize
+    }
+
+    /// Gets the number of output devices
+    ///
+    /// Read the number of the currently attached output devices from the server shared memory
+    /// without reading the device list.
+    pub fn output_device_count(&self) -> usize {
+        self.server_state.get_num_output_devs() as usize
+    }
+
+    /// Gets the number of input devices
+    ///
+    /// Read the number of the currently attached input devices from the server shared memory
+    /// without reading the device list.
+    pub fn input_device_count(&self) -> usize {
+        self.server_state.get_num_input_devs() as usize
+    }
+
+    /// Gets the number of output nodes
+    ///
+    /// Read the number of the currently attached output nodes from the server shared memory
+    /// without reading the node list.
+    pub fn output_node_count(&self) -> usize {
+        self.server_state.get_num_output_nodes() as usize
+    }
+
+    /// Gets the number of input nodes
+    ///
+    /// Read the number of the currently attached input nodes from the server shared memory
+    /// without reading the node list.
+    pub fn input_node_count(&self) -> usize {
+        self.server_state.get_num_input_nodes() as usize
+    }
+
+    /// Returns true if any output device is attached.
+    pub fn has_output_device(&self) -> bool {
+        self.output_device_count() > 0
+    }
+
+    /// Returns true if any input device is attached.
+    pub fn has_input_device(&self) -> bool {
+        self.input_device_count() > 0
+    }
+
+    /// Gets a list of output devices
+    ///
+    /// Read a list of the currently attached output devices from the server shared memory.
+    pub fn output_devices(&self) -> impl Iterator<Item = CrasIodevInfo> {
+        self.server_state.output_devices()
+    }
+
+    /// Gets a list of input devices
+    ///
+    /// Read a list of the currently attached input devices from the server shared memory.
+    pub fn input_devices(&self) -> impl Iterator<Item = CrasIodevInfo> {
+        self.server_state.input_devices()
+    }
+
+    /// Finds the attached device with `stable_id`, output devices first.
+    ///
+    /// Unlike `CrasIodevInfo::index`, the stable id of a device is kept across
+    /// reconnections and reboots, so it can be persisted to select the same device
+    /// later.
+    pub fn find_device_by_stable_id(&self, stable_id: u32) -> Option<CrasIodevInfo> {
+        self.output_devices()
+            .chain(self.input_devices())
+            .find(|dev| dev.stable_id == stable_id)
+    }
+
+    /// Gets a list of output nodes
+    ///
+    /// Read a list of the currently attached output nodes from the server shared memory.
+    ///
+    /// CRAS keeps no priority or preference for nodes, so there is no way to bias
+    /// which node is selected when one is plugged. The server only tracks whether
+    /// each node is plugged and when, in `CrasIonodeInfo::plugged_time`, and
+    /// selecting the active node is left to the system UI, which persists the user
+    /// preferences. A client which wants its own policy has to select the node
+    /// itself after each node change.
+    ///
+    /// Nodes have no latency offset either, neither measured nor calibrated, and
+    /// no message stores one. The delay of the device a stream plays to, including
+    /// the delay reported by a Bluetooth headset, is already part of the playback
+    /// timestamp of the stream, see `PlaybackBuffer::latency_bytes`. A user
+    /// calibration for A/V sync has to be stored and applied by the client.
+    pub fn output_nodes(&self) -> impl Iterator<Item = CrasIonodeInfo> {
+        self.server_state.output_nodes()
+    }
+
+    /// Gets the output nodes, the active node first, then the plugged nodes from
+    /// the most recently plugged, then the unplugged nodes.
+    ///
+    /// CRAS has no priority field to sort by, see `output_nodes`. This is the
+    /// order in which the server state says the nodes were preferred: the node it
+    /// plays to, then the nodes the user plugged from the latest one. Nodes which
+    /// compare equal, such as internal speakers plugged at boot, keep the server
+    /// order.
+    pub fn output_nodes_by_priority(&self) -> Vec<CrasIonodeInfo> {
+        let mut nodes: Vec<_> = self.output_nodes().collect();
+        sort_nodes_by_priority(&mut nodes);
+        nodes
+    }
+
+    /// Gets a list of input nodes
+    ///
+    /// Read a list of the currently attached input nodes from the server shared memory.
+    pub fn input_nodes(&self) -> impl Iterator<Item = CrasIonodeInfo> {
+        self.server_state.input_nodes()
+    }
+
+    /// Gets the input node of `node_type` to capture from.
+    ///
+    /// If multiple input nodes have `node_type`, the active one is chosen, otherwise the
+    /// most recently plugged one.
+    pub(crate) fn preferred_input_node(&self, node_type: CrasNodeType) -> Option<CrasIonodeInfo> {
+        self.input_nodes()
+            .filter(|node| node.node_type == node_type)
+            .max_by_key(|node| {
+                (
+                    node.active,
+                    node.plugged_time.tv_sec,
+                    node.plugged_time.tv_nsec,
+                )
+            })
+    }
+
+    /// Copies the raw bytes of the server state shared memory, for offline analysis
+    /// against the layout of `cras_server_state`.
+    ///
+    /// The copy is `size_of::<cras_server_state>()` bytes and may be torn if the
+    /// server updates the state concurrently. This is meant for diagnostics, use
+    /// the typed getters otherwise.
+    pub fn dump_server_state_raw(&self) -> Vec<u8> {
+        self.server_state.dump_raw()
+    }
+
+    /// Gets an owned snapshot of the devices and nodes.
+    ///
+    /// All the lists are read from the server shared memory in one consistent
+    /// read. Use `Topology::changed_since` to check whether a new snapshot may
+    /// differ from a previous one.
+    pub fn topology_snapshot(&self) -> Topology {
+        self.server_state.get_topology()
+    }
+
+    /// Gets the server's audio debug info.
+    ///
+    /// Sends a message to the server requesting an update of audio debug info,
+    /// waits for the response, and then reads the info from the server state.
+    ///
+    /// # Errors
+    ///
+    /// * If sending the message to the server failed.
+    /// * If an unexpected response message is received.
+    pub fn get_audio_debug_info(&mut self) -> Result<AudioDebugInfo> {
+        let header = cras_server_message {
+            length: mem::size_of::<cras_dump_audio_thread>() as u32,
+            id: CRAS_SERVER_MESSAGE_ID::CRAS_SERVER_DUMP_AUDIO_THREAD,
+        };
+        let msg = cras_dump_audio_thread { header };
+
+        self.server_socket.send_server_message_with_fds(&msg, &[])?;
+
+        match self.wait_for_reply()? {
+            ServerResult::DebugInfoReady => Ok(self.server_state.get_audio_debug_info()?),
+            _ => Err(Error::MessageTypeError),
+        }
+    }
+
+    /// Gets the streams of all the clients of the server, from the audio debug info.
+    ///
+    /// The top 16 bits of a stream id are the id of the client of the stream, see
+    /// `list_clients`. A stream attached to several devices is listed once per
+    /// device. The server removes the streams of a client when its connection is
+    /// closed, including when the client process dies, so no stream outlives its
+    /// client.
+    ///
+    /// The protocol has no message to close the stream of another client: the
+    /// server only disconnects a stream for the client which connected it, on any
+    /// build. A stuck stream is closed by closing the connection of its client.
+    ///
+    /// # Errors
+    ///
+    /// * If requesting the audio debug info from the server failed.
+    pub fn list_server_streams(&mut self) -> Result<Vec<AudioStreamDebugInfo>> {
+        Ok(self.get_audio_debug_info()?.streams)
+    }
+
+    /// Gets the clients attached to the server.
+    ///
+    /// The server lists its clients in the server state without their types, so
+    /// this requests the audio debug info, like `get_audio_debug_info` does, and
+    /// counts the streams of each client from it. The server lists at most
+    /// `CRAS_MAX_ATTACHED_CLIENTS` clients.
+    ///
+    /// # Errors
+    ///
+    /// * If requesting the audio debug info from the server failed.
+    pub fn list_clients(&mut self) -> Result<Vec<ClientInfo>> {
+        let debug_info = self.get_audio_debug_info()?;
+        Ok(self
+            .server_state
+            .attached_clients()
+            .map(|client| {
+                // The top 16 bits of a stream id are the id of its client.
+                let streams: Vec<_> = debug_info
+                    .streams
+                    .iter()
+                    .filter(|s| (s.stream_id >> 16) as u32 == client.id)
+                    .collect();
+                ClientInfo {
+                    id: client.id,
+                    pid: client.pid,
+                    uid: client.uid,
+                    gid: client.gid,
+                    client_type: streams.first().map(|s| s.client_type),
+                    num_streams: streams.len(),
+                }
+            })
+            .collect())
+    }
+
+    /// Gets the shared memory mapped by the client, for the server state and the
+    /// streams created by the client which are still attached to the server.
+    ///
+    /// Streams are not tracked after they are returned, so this requests the audio
+    /// debug info, like `get_audio_debug_info` does, to find the attached ones. See
+    /// `ShmUsage` for what is counted.
+    ///
+    /// # Errors
+    ///
+    /// * If requesting the audio debug info from the server failed.
+    pub fn shm_usage(&mut self) -> Result<ShmUsage> {
+        let debug_info = self.get_audio_debug_info()?;
+        self.stream_shm.retain(|usage| {
+            debug_info
+                .streams
+                .iter()
+                .any(|s| s.stream_id == u64::from(usage.stream_id))
+        });
+        Ok(ShmUsage {
+            server_state: mem::size_of::<cras_server_state>(),
+            streams: self.stream_shm.clone(),
+        })
+    }
+
+    /// Requests the server to dump its DSP pipeline information.
+    ///
+    /// Send a message to the server to request dumping the active DSP
+    /// configuration (EQ/DRC filters of each device). The server writes the
+    /// dump to its syslog only; it is not placed in the server state and no
+    /// response is returned, so the filters cannot be read back by the client.
+    ///
+    /// # Errors
+    ///
+    /// If writing the message to the server socket failed.
+    pub fn dump_dsp_info(&mut self) -> Result<()> {
+        let header = cras_server_message {
+            length: mem::size_of::<cras_dump_dsp_info>() as u32,
+            id: CRAS_SERVER_MESSAGE_ID::CRAS_SERVER_DUMP_DSP_INFO,
+        };
+        let msg = cras_dump_dsp_info { header };
+
+        self.server_socket.send_server_message_with_fds(&msg, &[])?;
+        Ok(())
+    }
+
+    /// Gets the frame rate the device of the active output node runs at.
+    ///
+    /// The device and node info in the server state do not carry the rate, so
+    /// this requests the audio debug info, which lists the open devices with their
+    /// rates. The rate of a device is usually not the rate of the streams on it,
+    /// which are resampled by the server.
+    ///
+    /// The rate of a device cannot be set by a client. The server opens a device at
+    /// the supported rate closest to the rate of the first stream attached to it,
+    /// and keeps it until the device is closed when its last stream is removed. A
+    /// device can only be brought to a given rate by being the first stream of the
+    /// device, at that rate, e.g. pinned to it while it is idle.
+    ///
+    /// # Returns
+    ///
+    /// The rate, or `None` if no output node is active or its device is not open.
+    ///
+    /// # Errors
+    ///
+    /// * If requesting the audio debug info from the server failed.
+    pub fn active_output_rate(&mut self) -> Result<Option<u32>> {
+        let iodev_index = match self.output_nodes().find(|node| node.active) {
+            Some(node) => node.iodev_index,
+            None => return Ok(None),
+        };
+        let debug_info = self.get_audio_debug_info()?;
+        Ok(debug_info
+            .devices
+            .iter()
+            .find(|dev| {
+                dev.dev_idx == iodev_index
+                    && dev.direction == CRAS_STREAM_DIRECTION::CRAS_STREAM_OUTPUT
+            })
+            .map(|dev| dev.frame_rate))
+    }
+
+    /// Whether the active output node is a Bluetooth node.
+    ///
+    /// The Bluetooth profile in use, A2DP or HFP, is not in the server state: a
+    /// headset has a single output node of type `CRAS_NODE_TYPE_BLUETOOTH` for
+    /// both, and the server switches the profile behind it. Only the narrowband
+    /// HFP input has its own node type, `CRAS_NODE_TYPE_BLUETOOTH_NB_MIC`.
+    ///
+    /// The protocol has no message to list or select the profiles of a headset
+    /// either. The server picks the profile from the use of the headset: it is
+    /// switched to HFP when its input node is selected, so the microphone can be
+    /// captured, and back to A2DP when another input node is selected.
+    pub fn active_output_is_bluetooth(&self) -> bool {
+        self.output_nodes()
+            .any(|node| node.active && node.node_type == CrasNodeType::CRAS_NODE_TYPE_BLUETOOTH)
+    }
+
+    /// Gets a diagnostics report of the client and the server for bug reports.
+    ///
+    /// The report contains the client id, client type and socket type, the system
+    /// volume and mutes, the devices and nodes, and the server's audio debug info in
+    /// the format of `cras_tests control dump_audio_debug_info`. No fds or
+    /// addresses are included.
+    ///
+    /// # Errors
+    ///
+    /// * If requesting the audio debug info from the server failed.
+    pub fn diagnostics(&mut self) -> Result<String> {
+        let debug_info = self.get_audio_debug_info()?;
+        let mut report = String::new();
+        // Writing to a String never fails.
+        let _ = self.write_diagnostics(&mut report, &debug_info);
+        Ok(report)
+    }
+
+    fn write_diagnostics(&self, out: &mut String, debug_info: &AudioDebugInfo) -> fmt::Result {
+        writeln!(out, "-------------client------------")?;
+        writeln!(out, "client_id: {}", self.client_id)?;
+        writeln!(out, "client_type: {:?}", self.client_type)?;
+        writeln!(out, "socket_type: {:?}", self.socket_type)?;
+
+        let volume_state = self.system_volume_state();
+        writeln!(out, "-------------system------------")?;
+        writeln!(out, "volume: {}", volume_state.scalar)?;
+        writeln!(out, "mute: {}", volume_state.muted)?;
+        writeln!(out, "user_mute: {}", self.server_state.get_user_mute())?;
+        writeln!(out, "capture_mute: {}", self.effective_capture_mute())?;
+        writeln!(out, "capture_mute_locked: {}", self.capture_mute_locked())?;
+
+        let topology = self.topology_snapshot();
+        writeln!(out, "-------------devices------------")?;
+        for dev in topology.output_devices.iter() {
+            writeln!(out, "output {}: {}", dev.index, dev.name)?;
+        }
+        for dev in topology.input_devices.iter() {
+            writeln!(out, "input {}: {}", dev.index, dev.name)?;
+        }
+        writeln!(out, "-------------nodes------------")?;
+        let nodes = topology
+            .output_nodes
+            .iter()
+            .map(|node| ("output", node))
+            .chain(topology.input_nodes.iter().map(|node| ("input", node)));
+        for (direction, node) in nodes {
+            writeln!(
+                out,
+                "{} {}:{} ({:08x}) {} type: {} plugged: {} active: {} volume: {} gain: {}",
+                direction,
+                node.iodev_index,
+                node.ionode_index,
+                node.stable_id,
+                node.name,
+                node.type_name,
+                node.plugged,
+                node.active,
+                node.volume,
+                node.capture_gain
+            )?;
+        }
+
+        writeln!(out, "Audio Debug Stats:")?;
+        writeln!(out, "-------------devices------------")?;
+        for device in &debug_info.devices {
+            writeln!(out, "{}", device)?;
+            writeln!(out)?;
+        }
+        writeln!(out, "-------------stream_dump------------")?;
+        for stream in &debug_info.streams {
+            writeln!(out, "{}", stream)?;
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    /// Checks whether the server accepts a stream of the given format.
+    ///
+    /// The server does not advertise the formats it supports, so this checks the
+    /// constraints the server applies to every stream when it is connected. All
+    /// `SampleFormat`s are supported by the server; it converts them to the format
+    /// of the device.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::InvalidFrameRate` - If `frame_rate` is out of the accepted range.
+    /// * `Error::InvalidNumChannels` - If `num_channels` is zero or more than the
+    ///   channels supported by the server.
+    pub fn validate_format(
+        &self,
+        _format: SampleFormat,
+        frame_rate: u32,
+        num_channels: usize,
+    ) -> Result<()> {
+        if !(MIN_FRAME_RATE..=MAX_FRAME_RATE).contains(&frame_rate) {
+            return Err(Error::InvalidFrameRate(frame_rate));
+        }
+        if num_channels == 0 || num_channels > CRAS_CHANNEL::CRAS_CH_MAX as usize {
+            return Err(Error::InvalidNumChannels(num_channels));
+        }
+        Ok(())
+    }
+
+    /// Gets the lowest and the highest frame rate the server accepts for a stream,
+    /// inclusive.
+    ///
+    /// The server does not report the rates of the devices, it resamples every
+    /// stream in this range to the rate of its device. Any rate in the range is
+    /// accepted, but playing at the rate of the device, usually 48000 or 44100,
+    /// avoids resampling; see `active_output_rate`.
+    pub fn supported_rate_range(&self) -> (u32, u32) {
+        (MIN_FRAME_RATE, MAX_FRAME_RATE)
+    }
+
+    /// Waits for the next system state change notified by the server.
+    ///
+    /// The first call registers the client for all the notifications in
+    /// `SystemEvent`, changes before that are not reported. Notifications received
+    /// while the client waits for the reply of another request are queued and
+    /// returned by later calls in the order they were received.
+    ///
+    /// # Errors
+    ///
+    /// * If sending the registration messages to the server failed.
+    /// * If a message other than a notification is received.
+    pub async fn next_system_event(
+        &mut self,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<SystemEvent> {
+        self.register_system_events()?;
+        let event = match self.pending_system_events.pop_front() {
+            Some(event) => event,
+            None => match CrasClient::async_wait_for_message(&mut self.server_socket, ex).await? {
+                ServerResult::SystemEvent(event) => event,
+                _ => return Err(Error::MessageTypeError),
+            },
+        };
+        Ok(self.queue_jack_changes(event))
+    }
+
+    // Registers the client for all the notifications in `SystemEvent`, once.
+    fn register_system_events(&mut self) -> Result<()> {
+        if !self.system_events_registered {
+            for &msg_id in SystemEvent::MESSAGE_IDS.iter() {
+                self.register_notification(msg_id, true)?;
+            }
+            self.system_events_registered = true;
+            self.jack_states = self.jack_states();
+        }
+        Ok(())
+    }
+
+    // Registers or unregisters the client for notifications of `msg_id`.
+    fn register_notification(
+        &mut self,
+        msg_id: CRAS_CLIENT_MESSAGE_ID,
+        register: bool,
+    ) -> Result<()> {
+        let header = cras_server_message {
+            length: mem::size_of::<cras_register_notification>() as u32,
+            id: CRAS_SERVER_MESSAGE_ID::CRAS_SERVER_REGISTER_NOTIFICATION,
+        };
+        let msg = cras_register_notification {
+            header,
+            msg_id: msg_id as u32,
+            do_register: register as i32,
+        };
+
+        self.server_socket.send_server_message_with_fds(&msg, &[])?;
+        Ok(())
+    }
+
+    // Gets next server_stream_id from client and increment stream_id counter.
+    fn next_server_stream_id(&mut self) -> u32 {
+        let res = self.next_stream_id;
+        self.next_stream_id += 1;
+        self.server_stream_id(res)
+    }
+
+    // Records the shared memory of a connected stream for `shm_usage`.
+    fn record_stream_shm(&mut self, stream_id: u32, samples: usize) {
+        self.stream_shm.push(StreamShmUsage {
+            stream_id,
+            header: mem::size_of::<cras_audio_shm_header>(),
+            samples,
+        });
     }
 
     // Gets server_stream_id from given stream_id
@@ -415,41 +2398,46 @@ impl<'a> CrasClient<'a> {
     }
 
     // Prepares and sends connect stream message to server
-    #[allow(clippy::too_many_arguments)]
     fn prepare_and_send_connect_stream(
         &mut self,
-        device_index: Option<u32>,
-        block_size: u32,
         direction: CRAS_STREAM_DIRECTION,
-        rate: u32,
-        channel_num: usize,
-        format: SampleFormat,
-        effects: &[StreamEffect],
+        params: &StreamParams,
         client_shm_size: u64,
         buffer_offsets: [u64; 2],
         fds: &[RawFd],
     ) -> Result<u32> {
+        self.validate_format(params.format, params.frame_rate, params.num_channels)?;
         let stream_id = self.next_server_stream_id();
 
         // Prepares server message
-        let audio_format =
-            cras_audio_format_packed::new(format.into(), rate, channel_num, direction);
+        let block_size = params.buffer_size as u32;
+        let audio_format = cras_audio_format_packed::new(
+            params.format.into(),
+            params.frame_rate,
+            params.num_channels,
+            direction,
+        );
         let msg_header = cras_server_message {
             length: mem::size_of::<cras_connect_message>() as u32,
             id: CRAS_SERVER_MESSAGE_ID::CRAS_SERVER_CONNECT_STREAM,
         };
         let server_cmsg = cras_connect_message {
             header: msg_header,
-            proto_version: CRAS_PROTO_VER,
+            proto_version: self.proto_version,
             direction,
             stream_id,
-            stream_type: self.stream_type,
+            stream_type: params.stream_type.unwrap_or(self.stream_type),
             buffer_frames: block_size,
             cb_threshold: block_size,
-            flags: 0,
+            flags: match direction {
+                CRAS_STREAM_DIRECTION::CRAS_STREAM_INPUT => params.flags,
+                _ => 0,
+            },
             format: audio_format,
-            dev_idx: device_index.unwrap_or(CRAS_SPECIAL_DEVICE::NO_DEVICE as u32),
-            effects: effects.iter().collect::<CrasStreamEffect>().into(),
+            dev_idx: params
+                .device_index
+                .unwrap_or(CRAS_SPECIAL_DEVICE::NO_DEVICE as u32),
+            effects: params.effects.into(),
             client_type: self.client_type,
             client_shm_size,
             buffer_offsets,
@@ -463,152 +2451,174 @@ impl<'a> CrasClient<'a> {
     }
 
     // Creates general stream with given parameters
-    #[allow(clippy::too_many_arguments)]
     fn create_stream<'b, T: BufferCommit + CrasStreamData<'b>>(
         &mut self,
-        device_index: Option<u32>,
-        block_size: u32,
         direction: CRAS_STREAM_DIRECTION,
-        rate: u32,
-        channel_num: usize,
-        format: SampleFormat,
-        effects: &[StreamEffect],
+        params: &StreamParams,
+    ) -> Result<CrasStream<'b, T>> {
+        // Allocates the samples area if the client requests its size. The server
+        // sends it back as the samples fd of the stream.
+        let used_size =
+            (params.buffer_size * params.num_channels * params.format.sample_bytes()) as u64;
+        let client_shm: Option<File> = match params.client_shm_size {
+            Some(size) => {
+                let min_size = used_size * CRAS_NUM_SHM_BUFFERS as u64;
+                if size < min_size {
+                    return Err(Error::ClientShmTooSmall(size, min_size));
+                }
+                let name = CString::new("cras-client-shm").map_err(io::Error::from)?;
+                Some(libchromeos::sys::unix::SharedMemory::new(&name, size)?.into())
+            }
+            None => None,
+        };
+        let client_shm = client_shm
+            .as_ref()
+            .map(|shm| (shm.as_raw_fd(), params.client_shm_size.unwrap_or(0), [0, used_size]));
+        let mut stream = self.connect_stream(direction, params, client_shm)?;
+        if self.restore_streams {
+            let alive = Arc::new(());
+            self.tracked_streams.retain(|s| s.alive.upgrade().is_some());
+            self.tracked_streams.push(TrackedStream {
+                stream_id: stream.stream_id(),
+                direction,
+                params: params.clone(),
+                alive: Arc::downgrade(&alive),
+            });
+            stream.set_restore_token(alive);
+        }
+        Ok(stream)
+    }
+
+    // Connects a stream with given parameters, and with the samples area in the
+    // given client shm fd of the given size at the given buffer offsets if any.
+    fn connect_stream<'b, T: BufferCommit + CrasStreamData<'b>>(
+        &mut self,
+        direction: CRAS_STREAM_DIRECTION,
+        params: &StreamParams,
+        client_shm: Option<(RawFd, u64, [u64; 2])>,
     ) -> Result<CrasStream<'b, T>> {
-        assert!(direction == CRAS_STREAM_DIRECTION::CRAS_STREAM_OUTPUT || self.cras_capture);
+        if direction != CRAS_STREAM_DIRECTION::CRAS_STREAM_OUTPUT && !self.cras_capture {
+            return Err(Error::CaptureNotPermitted);
+        }
 
         let (sock1, sock2) = UnixStream::pair()?;
+        let mut fds = vec![sock2.as_raw_fd()];
+        let mut client_shm_size = 0;
+        let mut buffer_offsets = [0, 0];
+        if let Some((fd, size, offsets)) = client_shm {
+            fds.push(fd);
+            client_shm_size = size;
+            buffer_offsets = offsets;
+        }
         let stream_id = self.prepare_and_send_connect_stream(
-            device_index,
-            block_size,
             direction,
-            rate,
-            channel_num,
-            format,
-            effects,
-            0,
-            [0, 0],
-            &[sock2.as_raw_fd()],
+            params,
+            client_shm_size,
+            buffer_offsets,
+            &fds,
         )?;
 
         let audio_socket = AudioSocket::new(sock1);
-        loop {
-            let result = CrasClient::wait_for_message(&mut self.server_socket)?;
-            if let ServerResult::StreamConnected(_stream_id, header_fd, samples_fd) = result {
-                return CrasStream::try_new(
-                    stream_id,
-                    self.server_socket.try_clone()?,
-                    block_size,
-                    direction,
-                    rate,
-                    channel_num,
-                    format.into(),
-                    audio_socket,
-                    header_fd,
-                    samples_fd,
-                )
-                .map_err(Error::CrasStreamError);
-            }
+        let (header_fd, samples_fd, format) =
+            self.wait_for_stream_connected(stream_id, params.connect_timeout)?;
+        self.record_stream_shm(stream_id, samples_fd.size());
+        let mut stream = CrasStream::try_new(
+            stream_id,
+            self.server_socket.try_clone()?,
+            params.buffer_size as u32,
+            direction,
+            params.frame_rate,
+            params.num_channels,
+            params.format.into(),
+            audio_socket,
+            header_fd,
+            samples_fd,
+        )?;
+        stream.set_trigger_only(
+            direction == CRAS_STREAM_DIRECTION::CRAS_STREAM_INPUT && params.is_trigger_only(),
+        );
+        stream.set_connected_format(&format);
+        if let Some(volume) = params.initial_volume {
+            stream.set_volume_scaler(f32::from(volume) / 100.0);
         }
+        Ok(stream)
     }
 
     // Creates general stream with given parameters
-    #[allow(clippy::too_many_arguments)]
     fn create_async_stream<'b, T: async_::CrasStreamData<'b> + AsyncBufferCommit>(
         &mut self,
-        device_index: Option<u32>,
-        block_size: u32,
         direction: CRAS_STREAM_DIRECTION,
-        rate: u32,
-        channel_num: usize,
-        format: SampleFormat,
-        effects: &[StreamEffect],
+        params: &StreamParams,
         ex: &dyn AudioStreamsExecutor,
     ) -> Result<async_::CrasStream<'b, T>> {
-        assert!(direction == CRAS_STREAM_DIRECTION::CRAS_STREAM_OUTPUT || self.cras_capture);
+        if direction != CRAS_STREAM_DIRECTION::CRAS_STREAM_OUTPUT && !self.cras_capture {
+            return Err(Error::CaptureNotPermitted);
+        }
 
         let (sock1, sock2) = UnixStream::pair()?;
         let stream_id = self.prepare_and_send_connect_stream(
-            device_index,
-            block_size,
             direction,
-            rate,
-            channel_num,
-            format,
-            effects,
+            params,
             0,
             [0, 0],
             &[sock2.as_raw_fd()],
         )?;
-
-        let audio_socket = async_::AudioSocket::new(sock1, ex)?;
-        loop {
-            let result = CrasClient::wait_for_message(&mut self.server_socket)?;
-            if let ServerResult::StreamConnected(_stream_id, header_fd, samples_fd) = result {
-                return async_::CrasStream::try_new(
-                    stream_id,
-                    self.server_socket.try_clone()?,
-                    block_size,
-                    direction,
-                    rate,
-                    channel_num,
-                    format.into(),
-                    audio_socket,
-                    header_fd,
-                    samples_fd,
-                )
-                .map_err(Error::CrasStreamError);
-            }
-        }
+
+        let audio_socket = async_::AudioSocket::new(sock1, ex)?;
+        let (header_fd, samples_fd, _format) =
+            self.wait_for_stream_connected(stream_id, params.connect_timeout)?;
+        self.record_stream_shm(stream_id, samples_fd.size());
+        Ok(async_::CrasStream::try_new(
+            stream_id,
+            self.server_socket.try_clone()?,
+            params.buffer_size as u32,
+            direction,
+            params.frame_rate,
+            params.num_channels,
+            params.format.into(),
+            audio_socket,
+            header_fd,
+            samples_fd,
+        )?)
     }
 
     // Creates general stream asynchronously with given parameters
-    #[allow(clippy::too_many_arguments)]
     async fn async_create_async_stream<'b, T: async_::CrasStreamData<'b> + AsyncBufferCommit>(
         &mut self,
-        device_index: Option<u32>,
-        block_size: u32,
         direction: CRAS_STREAM_DIRECTION,
-        rate: u32,
-        channel_num: usize,
-        format: SampleFormat,
-        effects: &[StreamEffect],
+        params: &StreamParams,
         ex: &dyn AudioStreamsExecutor,
     ) -> Result<async_::CrasStream<'b, T>> {
-        assert!(direction == CRAS_STREAM_DIRECTION::CRAS_STREAM_OUTPUT || self.cras_capture);
+        if direction != CRAS_STREAM_DIRECTION::CRAS_STREAM_OUTPUT && !self.cras_capture {
+            return Err(Error::CaptureNotPermitted);
+        }
 
         let (sock1, sock2) = UnixStream::pair()?;
         let stream_id = self.prepare_and_send_connect_stream(
-            device_index,
-            block_size,
             direction,
-            rate,
-            channel_num,
-            format,
-            effects,
+            params,
             0,
             [0, 0],
             &[sock2.as_raw_fd()],
         )?;
 
         let audio_socket = async_::AudioSocket::new(sock1, ex)?;
-        loop {
-            let result = CrasClient::async_wait_for_message(&mut self.server_socket, ex).await?;
-            if let ServerResult::StreamConnected(_stream_id, header_fd, samples_fd) = result {
-                return async_::CrasStream::try_new(
-                    stream_id,
-                    self.server_socket.try_clone()?,
-                    block_size,
-                    direction,
-                    rate,
-                    channel_num,
-                    format.into(),
-                    audio_socket,
-                    header_fd,
-                    samples_fd,
-                )
-                .map_err(Error::CrasStreamError);
-            }
-        }
+        let (header_fd, samples_fd, _format) = self
+            .async_wait_for_stream_connected(stream_id, params.connect_timeout, ex)
+            .await?;
+        self.record_stream_shm(stream_id, samples_fd.size());
+        Ok(async_::CrasStream::try_new(
+            stream_id,
+            self.server_socket.try_clone()?,
+            params.buffer_size as u32,
+            direction,
+            params.frame_rate,
+            params.num_channels,
+            params.format.into(),
+            audio_socket,
+            header_fd,
+            samples_fd,
+        )?)
     }
 
     /// Creates a new playback stream pinned to the device at `device_index`.
@@ -620,7 +2630,10 @@ impl<'a> CrasClient<'a> {
     /// * `format` - The format to use for stream audio samples.
     /// * `frame_rate` - The sample rate of the stream.
     /// * `buffer_size` - The transfer size granularity in frames.
+    /// * `initial_volume` - The volume of the stream in percent. It is applied before
+    ///   the stream is returned, see `StreamParams::initial_volume`.
     #[allow(clippy::type_complexity)]
+    #[allow(clippy::too_many_arguments)]
     pub fn new_pinned_playback_stream(
         &mut self,
         device_index: u32,
@@ -629,18 +2642,20 @@ impl<'a> CrasClient<'a> {
         frame_rate: u32,
         buffer_size: usize,
         effects: &[StreamEffect],
+        initial_volume: Option<u8>,
     ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn PlaybackBufferStream>), BoxError>
     {
+        let mut params = StreamParams::new(num_channels, format, frame_rate, buffer_size)
+            .device_index(device_index)
+            .effects(effects);
+        if let Some(volume) = initial_volume {
+            params = params.initial_volume(volume);
+        }
         Ok((
             Box::new(NoopStreamControl::new()),
             Box::new(self.create_stream::<CrasPlaybackData>(
-                Some(device_index),
-                buffer_size as u32,
                 CRAS_STREAM_DIRECTION::CRAS_STREAM_OUTPUT,
-                frame_rate,
-                num_channels,
-                format,
-                effects,
+                &params,
             )?),
         ))
     }
@@ -667,43 +2682,330 @@ impl<'a> CrasClient<'a> {
         buffer_size: usize,
         effects: &[StreamEffect],
     ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn CaptureBufferStream>), BoxError> {
-        assert!(self.cras_capture);
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(self.create_stream::<CrasCaptureData>(
+                CRAS_STREAM_DIRECTION::CRAS_STREAM_INPUT,
+                &StreamParams::new(num_channels, format, frame_rate, buffer_size)
+                    .device_index(device_index)
+                    .effects(effects),
+            )?),
+        ))
+    }
+
+    /// Creates a new playback `CrasStream` with `params`.
+    ///
+    /// Unlike `new_playback_stream`, the concrete `CrasStream` is returned, which gives
+    /// access to stream specific functions such as `CrasStream::take_event_receiver`.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::StreamConnectRejected` - If the server rejected the parameters of the
+    ///   stream, e.g. a direction the client type may not use.
+    /// * `Error::StreamConnectFailed` - If the server failed to set up the stream.
+    /// * `Error::StreamConnectTimeout` - If the server did not reply within the
+    ///   `StreamParams::connect_timeout`. The stream is disconnected in case the
+    ///   server still connects it.
+    /// * If sending the connect message failed.
+    pub fn new_cras_playback_stream<'b>(
+        &mut self,
+        params: &StreamParams,
+    ) -> Result<CrasStream<'b, CrasPlaybackData<'b>>> {
+        self.create_stream(CRAS_STREAM_DIRECTION::CRAS_STREAM_OUTPUT, params)
+    }
+
+    /// Creates a new playback `CrasStream` with `params`, whose samples area is the
+    /// caller's `client_shm` with the two buffers at `buffer_offsets`.
+    ///
+    /// The stream maps the same memory, so the buffers of `next_playback_buffer`
+    /// are views of `client_shm`. A caller which already has the samples in
+    /// `client_shm`, such as a virtio device sharing guest memory with the host,
+    /// commits them without a copy with `CrasStream::commit_frames_at`.
+    /// `StreamParams::client_shm_size` is ignored.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::ClientShmTooSmall` - If a buffer of `buffer_size` frames at one of
+    ///   `buffer_offsets` does not fit in `client_shm`.
+    /// * If connecting the stream failed, see `new_cras_playback_stream`.
+    pub fn new_playback_stream_with_shm<'b, E: std::error::Error>(
+        &mut self,
+        params: &StreamParams,
+        client_shm: &dyn SharedMemory<Error = E>,
+        buffer_offsets: [u64; 2],
+    ) -> Result<CrasStream<'b, CrasPlaybackData<'b>>> {
+        let used_size =
+            (params.buffer_size * params.num_channels * params.format.sample_bytes()) as u64;
+        let min_size = buffer_offsets.iter().max().unwrap_or(&0) + used_size;
+        if client_shm.size() < min_size {
+            return Err(Error::ClientShmTooSmall(client_shm.size(), min_size));
+        }
+        self.connect_stream(
+            CRAS_STREAM_DIRECTION::CRAS_STREAM_OUTPUT,
+            params,
+            Some((client_shm.as_raw_fd(), client_shm.size(), buffer_offsets)),
+        )
+    }
+
+    /// Creates a new capture `CrasStream` with `params`.
+    ///
+    /// Unlike `new_capture_stream`, the concrete `CrasStream` is returned, which gives
+    /// access to stream specific functions such as `CrasStream::take_event_receiver`.
+    /// Capture must have been enabled by `enable_cras_capture`.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::CaptureNotPermitted` - If capture is not enabled.
+    /// * If connecting the stream failed, see `new_cras_playback_stream`.
+    pub fn new_cras_capture_stream<'b>(
+        &mut self,
+        params: &StreamParams,
+    ) -> Result<CrasStream<'b, CrasCaptureData<'b>>> {
+        self.create_stream(CRAS_STREAM_DIRECTION::CRAS_STREAM_INPUT, params)
+    }
+
+    /// Plays a sine tone of `frequency` Hz for `duration` on the device at
+    /// `device_index`, e.g. to test a speaker, and blocks until the whole tone is
+    /// committed to the stream.
+    ///
+    /// The tone is played at half of full scale by a mono stream pinned to the
+    /// device, so it follows the system volume and mute. `TEST_TONE_FREQUENCY` is
+    /// the usual 440 Hz tone.
+    ///
+    /// # Errors
+    ///
+    /// * If connecting the stream failed, see `new_cras_playback_stream`.
+    /// * If writing the tone to the stream failed.
+    pub fn play_test_tone(
+        &mut self,
+        device_index: u32,
+        frequency: f32,
+        duration: Duration,
+    ) -> Result<()> {
+        let format = SampleFormat::S16LE;
+        let params = StreamParams::new(1, format, TEST_TONE_FRAME_RATE, TEST_TONE_BUFFER_SIZE)
+            .device_index(device_index);
+        let mut stream = self.new_cras_playback_stream(&params)?;
+        let mut playback = BufferedPlayback::new(&mut stream, 1, format, TEST_TONE_BUFFER_SIZE);
+
+        let num_frames = (duration.as_secs_f64() * f64::from(TEST_TONE_FRAME_RATE)) as usize;
+        let step = 2.0 * PI * f64::from(frequency) / f64::from(TEST_TONE_FRAME_RATE);
+        let mut block = Vec::with_capacity(TEST_TONE_BUFFER_SIZE * 2);
+        for start in (0..num_frames).step_by(TEST_TONE_BUFFER_SIZE) {
+            block.clear();
+            for frame in start..num_frames.min(start + TEST_TONE_BUFFER_SIZE) {
+                let sample = (step * frame as f64).sin() as f32 * TEST_TONE_AMPLITUDE;
+                block.extend_from_slice(&(sample as i16).to_le_bytes());
+            }
+            playback.write_all(&block)?;
+        }
+        playback.flush()?;
+        Ok(())
+    }
+
+    /// Creates a new capture stream pinned to the device of an input node with `node_type`.
+    ///
+    /// If multiple input nodes have `node_type`, the active one is chosen, otherwise the
+    /// most recently plugged one.
+    ///
+    /// # Arguments
+    ///
+    /// * `node_type` - The type of the input node to capture from.
+    /// * `num_channels` - The count of audio channels for the stream.
+    /// * `format` - The format to use for stream audio samples.
+    /// * `frame_rate` - The sample rate of the stream.
+    /// * `buffer_size` - The transfer size granularity in frames.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::NodeTypeNotFound` - If no input node has `node_type`.
+    /// * If connecting the stream failed.
+    #[allow(clippy::type_complexity)]
+    pub fn new_capture_stream_for_node_type(
+        &mut self,
+        node_type: CrasNodeType,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        effects: &[StreamEffect],
+    ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn CaptureBufferStream>), BoxError> {
+        let node = self
+            .preferred_input_node(node_type)
+            .ok_or(Error::NodeTypeNotFound(node_type))?;
+
+        self.new_pinned_capture_stream(
+            node.iodev_index,
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size,
+            effects,
+        )
+    }
+
+    /// Creates a new capture stream recording the output path at `source`, from its
+    /// loopback device.
+    ///
+    /// The server does not report the level of its output, a `PeakMeter` gets it
+    /// from a loopback stream.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The point of the output path to capture.
+    /// * `num_channels` - The count of audio channels for the stream.
+    /// * `format` - The format to use for stream audio samples.
+    /// * `frame_rate` - The sample rate of the stream.
+    /// * `buffer_size` - The transfer size granularity in frames.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::NodeTypeNotFound` - If the server has no loopback device for
+    ///   `source`.
+    /// * If connecting the stream failed.
+    #[allow(clippy::type_complexity)]
+    pub fn new_loopback_capture_stream(
+        &mut self,
+        source: LoopbackSource,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+    ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn CaptureBufferStream>), BoxError> {
+        self.new_capture_stream_for_node_type(
+            source.node_type(),
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size,
+            &[],
+        )
+    }
 
+    /// Creates a new capture stream which runs `effects` in the given `EffectMode`.
+    ///
+    /// Only effects that have a DSP implementation (echo cancellation, noise
+    /// suppression and gain control) can be offloaded. The server does not report
+    /// per stream whether an effect is offloaded, so the returned mode is derived
+    /// from the system-wide DSP support reported in the server state.
+    ///
+    /// # Arguments
+    ///
+    /// * `num_channels` - The count of audio channels for the stream.
+    /// * `format` - The format to use for stream audio samples.
+    /// * `frame_rate` - The sample rate of the stream.
+    /// * `buffer_size` - The transfer size granularity in frames.
+    /// * `effects` - The effects to apply to the stream.
+    /// * `mode` - The requested `EffectMode` of `effects`.
+    ///
+    /// # Returns
+    ///
+    /// * The stream control, the capture stream and the `EffectMode` which is
+    ///   actually used, either `EffectMode::Cpu` or `EffectMode::Dsp`.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::DspEffectUnsupported` - If `mode` is `EffectMode::Dsp` and any of
+    ///   `effects` cannot run on DSP.
+    /// * If connecting the stream failed.
+    #[allow(clippy::type_complexity)]
+    pub fn new_capture_stream_with_effect_mode(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        effects: &[StreamEffect],
+        mode: EffectMode,
+    ) -> std::result::Result<
+        (
+            Box<dyn StreamControl>,
+            Box<dyn CaptureBufferStream>,
+            EffectMode,
+        ),
+        BoxError,
+    > {
+        let (cras_effects, used_mode) = self.effects_with_mode(effects, mode)?;
         Ok((
             Box::new(NoopStreamControl::new()),
             Box::new(self.create_stream::<CrasCaptureData>(
-                Some(device_index),
-                buffer_size as u32,
                 CRAS_STREAM_DIRECTION::CRAS_STREAM_INPUT,
-                frame_rate,
-                num_channels,
-                format,
-                effects,
+                &StreamParams {
+                    effects: cras_effects,
+                    ..StreamParams::new(num_channels, format, frame_rate, buffer_size)
+                },
             )?),
+            used_mode,
         ))
     }
 
-    // Blocks handling the first server message received from `socket`.
-    fn wait_for_message(socket: &mut CrasServerSocket) -> Result<ServerResult> {
-        #[derive(PollToken)]
-        enum Token {
-            ServerMsg,
+    // Adds the DSP allowed bits of `effects` according to `mode` and returns the
+    // effects to request along with the mode which will be used.
+    fn effects_with_mode(
+        &self,
+        effects: &[StreamEffect],
+        mode: EffectMode,
+    ) -> Result<(CrasStreamEffect, EffectMode)> {
+        let mut cras_effects: CrasStreamEffect = effects.iter().collect();
+        if mode == EffectMode::Cpu {
+            return Ok((cras_effects, EffectMode::Cpu));
+        }
+
+        let dsp_effects = [
+            (
+                CrasStreamEffect::APM_ECHO_CANCELLATION,
+                CrasStreamEffect::DSP_ECHO_CANCELLATION_ALLOWED,
+                self.server_state.get_aec_on_dsp_supported(),
+            ),
+            (
+                CrasStreamEffect::APM_NOISE_SUPRESSION,
+                CrasStreamEffect::DSP_NOISE_SUPPRESSION_ALLOWED,
+                self.server_state.get_ns_on_dsp_supported(),
+            ),
+            (
+                CrasStreamEffect::APM_GAIN_CONTROL,
+                CrasStreamEffect::DSP_GAIN_CONTROL_ALLOWED,
+                self.server_state.get_agc_on_dsp_supported(),
+            ),
+        ];
+        let mut requested = false;
+        let mut unsupported = CrasStreamEffect::empty();
+        for (effect, allowed, supported) in dsp_effects {
+            if (cras_effects & effect).0 == 0 {
+                continue;
+            }
+            requested = true;
+            cras_effects |= allowed;
+            if !supported {
+                unsupported |= effect;
+            }
+        }
+
+        match mode {
+            EffectMode::Dsp if unsupported.0 != 0 => Err(Error::DspEffectUnsupported(unsupported)),
+            _ if requested && unsupported.0 == 0 => Ok((cras_effects, EffectMode::Dsp)),
+            _ => Ok((cras_effects, EffectMode::Cpu)),
         }
-        let poll_ctx: PollContext<Token> =
-            PollContext::new().and_then(|pc| pc.add(socket, Token::ServerMsg).and(Ok(pc)))?;
+    }
 
-        let events = poll_ctx.wait()?;
+    // Blocks handling the first server message received from `socket`, which is
+    // registered to `poll_ctx`.
+    fn wait_for_message(
+        poll_ctx: &PollContext<ServerToken>,
+        socket: &mut CrasServerSocket,
+        timeout: Option<Duration>,
+    ) -> Result<ServerResult> {
+        let events = match timeout {
+            Some(timeout) => poll_ctx.wait_timeout(timeout)?,
+            None => poll_ctx.wait()?,
+        };
         // Check the first readable message
-        let tokens: Vec<Token> = events.iter_readable().map(|e| e.token()).collect();
-        tokens
-            .get(0)
-            .ok_or(Error::UnexpectedExit)
-            .and_then(|ref token| {
-                match token {
-                    Token::ServerMsg => ServerResult::handle_server_message(socket),
-                }
-                .map_err(Into::into)
-            })
+        let token = events.iter_readable().next().map(|e| e.token());
+        match token {
+            Some(ServerToken::ServerMsg) => Ok(ServerResult::handle_server_message(socket)?),
+            None if timeout.is_some() => Err(Error::TimedOut),
+            None => Err(Error::UnexpectedExit),
+        }
     }
 
     async fn async_wait_for_message(
@@ -711,7 +3013,204 @@ impl<'a> CrasClient<'a> {
         ex: &dyn AudioStreamsExecutor,
     ) -> Result<ServerResult> {
         ex.wait_fd_readable(socket.try_clone()?.as_raw_fd()).await?;
-        Self::wait_for_message(socket)
+        // The socket is readable, so the message is handled without polling.
+        Ok(ServerResult::handle_server_message(socket)?)
+    }
+
+    // Blocks until a server message which is not a system event is received. System
+    // events received before it are kept for `next_system_event`.
+    fn wait_for_reply(&mut self) -> Result<ServerResult> {
+        self.wait_for_reply_timeout(None)
+    }
+
+    // Like `wait_for_reply`, but fails with `Error::TimedOut` if no reply is received
+    // within `timeout`.
+    fn wait_for_reply_timeout(&mut self, timeout: Option<Duration>) -> Result<ServerResult> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let remaining = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            let message =
+                CrasClient::wait_for_message(&self.poll_ctx, &mut self.server_socket, remaining)?;
+            match message {
+                ServerResult::SystemEvent(event) => self.pending_system_events.push_back(event),
+                result => return Ok(result),
+            }
+        }
+    }
+
+    // Blocks until a system event for which `confirms` returns `true` is received,
+    // failing with `Error::TimedOut` at `deadline`. Only messages received from now
+    // on are checked, see `confirmed_event`.
+    fn wait_for_system_event(
+        &mut self,
+        deadline: Instant,
+        confirms: impl Fn(&SystemEvent) -> bool,
+    ) -> Result<SystemEvent> {
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let message = CrasClient::wait_for_message(
+                &self.poll_ctx,
+                &mut self.server_socket,
+                Some(remaining),
+            )?;
+            if let Some(event) = self.confirmed_event(message, &confirms) {
+                return Ok(event);
+            }
+        }
+    }
+
+    // Like `wait_for_system_event`, but waits on `ex`, failing with
+    // `Error::TimedOut` after `timeout`.
+    async fn async_wait_for_system_event(
+        &mut self,
+        timeout: Duration,
+        ex: &dyn AudioStreamsExecutor,
+        confirms: impl Fn(&SystemEvent) -> bool,
+    ) -> Result<SystemEvent> {
+        let result = {
+            let confirmed = self.async_next_confirmed_event(ex, &confirms);
+            let delay = ex.delay(timeout);
+            pin_mut!(confirmed, delay);
+            match future::select(confirmed, delay).await {
+                Either::Left((result, _)) => Some(result),
+                Either::Right((result, _)) => {
+                    result?;
+                    None
+                }
+            }
+        };
+        result.unwrap_or(Err(Error::TimedOut))
+    }
+
+    async fn async_next_confirmed_event(
+        &mut self,
+        ex: &dyn AudioStreamsExecutor,
+        confirms: &impl Fn(&SystemEvent) -> bool,
+    ) -> Result<SystemEvent> {
+        loop {
+            let message = CrasClient::async_wait_for_message(&mut self.server_socket, ex).await?;
+            if let Some(event) = self.confirmed_event(message, confirms) {
+                return Ok(event);
+            }
+        }
+    }
+
+    // Returns the system event `message` if `confirms` returns `true` for it. Other
+    // system events are kept for `next_system_event`, after the events already
+    // queued, and other messages are dropped.
+    fn confirmed_event(
+        &mut self,
+        message: ServerResult,
+        confirms: &impl Fn(&SystemEvent) -> bool,
+    ) -> Option<SystemEvent> {
+        match message {
+            ServerResult::SystemEvent(event) if confirms(&event) => Some(event),
+            ServerResult::SystemEvent(event) => {
+                self.pending_system_events.push_back(event);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    // Waits for the reply to the connect message of the stream `stream_id`, failing
+    // with `Error::StreamConnectTimeout` if it is not received within `timeout`.
+    fn wait_for_stream_connected(
+        &mut self,
+        stream_id: u32,
+        timeout: Option<Duration>,
+    ) -> Result<StreamConnectedReply> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let remaining = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            let result = match self.wait_for_reply_timeout(remaining) {
+                Err(Error::TimedOut) => Err(self.cancel_stream_connect(stream_id, timeout)),
+                result => result,
+            }?;
+            if let Some(reply) = CrasClient::stream_connect_reply(stream_id, result) {
+                return reply;
+            }
+        }
+    }
+
+    async fn async_wait_for_stream_connected(
+        &mut self,
+        stream_id: u32,
+        timeout: Option<Duration>,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<StreamConnectedReply> {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return self.async_wait_for_stream_replies(stream_id, ex).await,
+        };
+        let result = {
+            let connected = self.async_wait_for_stream_replies(stream_id, ex);
+            let delay = ex.delay(timeout);
+            pin_mut!(connected, delay);
+            match future::select(connected, delay).await {
+                Either::Left((result, _)) => Some(result),
+                Either::Right((result, _)) => {
+                    result?;
+                    None
+                }
+            }
+        };
+        result.unwrap_or_else(|| Err(self.cancel_stream_connect(stream_id, Some(timeout))))
+    }
+
+    async fn async_wait_for_stream_replies(
+        &mut self,
+        stream_id: u32,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<StreamConnectedReply> {
+        loop {
+            let result = self.async_wait_for_reply(ex).await?;
+            if let Some(reply) = CrasClient::stream_connect_reply(stream_id, result) {
+                return reply;
+            }
+        }
+    }
+
+    // Returns the result of connecting the stream `stream_id` if `result` is the
+    // reply to its connect message. The replies to connect messages which timed out
+    // are dropped, which unmaps their shared memory.
+    fn stream_connect_reply(
+        stream_id: u32,
+        result: ServerResult,
+    ) -> Option<Result<StreamConnectedReply>> {
+        match result {
+            ServerResult::StreamConnected(id, header_fd, samples_fd, format) if id == stream_id => {
+                Some(Ok((header_fd, samples_fd, format)))
+            }
+            ServerResult::StreamConnectFailed(id, err) if id == stream_id => {
+                Some(Err(match err {
+                    e if e == -libc::EINVAL => Error::StreamConnectRejected,
+                    e => Error::StreamConnectFailed(e),
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    // Disconnects a stream whose connect reply timed out, in case the server still
+    // connects it, and returns the timeout error.
+    fn cancel_stream_connect(&self, stream_id: u32, timeout: Option<Duration>) -> Error {
+        if let Err(e) = self.server_socket.disconnect_stream(stream_id) {
+            warn!("Failed to disconnect stream {:x}: {}", stream_id, e);
+        }
+        Error::StreamConnectTimeout(timeout.unwrap_or_default())
+    }
+
+    async fn async_wait_for_reply(
+        &mut self,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<ServerResult> {
+        loop {
+            match CrasClient::async_wait_for_message(&mut self.server_socket, ex).await? {
+                ServerResult::SystemEvent(event) => self.pending_system_events.push_back(event),
+                result => return Ok(result),
+            }
+        }
     }
 
     /// Returns any open file descriptors needed by CrasClient.
@@ -735,13 +3234,8 @@ impl<'a> StreamSource for CrasClient<'a> {
         Ok((
             Box::new(NoopStreamControl::new()),
             Box::new(self.create_stream::<CrasPlaybackData>(
-                None,
-                buffer_size as u32,
                 CRAS_STREAM_DIRECTION::CRAS_STREAM_OUTPUT,
-                frame_rate,
-                num_channels,
-                format,
-                &[],
+                &StreamParams::new(num_channels, format, frame_rate, buffer_size),
             )?),
         ))
     }
@@ -759,13 +3253,8 @@ impl<'a> StreamSource for CrasClient<'a> {
         Ok((
             Box::new(NoopStreamControl::new()),
             Box::new(self.create_async_stream::<async_::CrasPlaybackData>(
-                None,
-                buffer_size as u32,
                 CRAS_STREAM_DIRECTION::CRAS_STREAM_OUTPUT,
-                frame_rate,
-                num_channels,
-                format,
-                &[],
+                &StreamParams::new(num_channels, format, frame_rate, buffer_size),
                 ex,
             )?),
         ))
@@ -785,13 +3274,8 @@ impl<'a> StreamSource for CrasClient<'a> {
             Box::new(NoopStreamControl::new()),
             Box::new(
                 self.async_create_async_stream::<async_::CrasPlaybackData>(
-                    None,
-                    buffer_size as u32,
                     CRAS_STREAM_DIRECTION::CRAS_STREAM_OUTPUT,
-                    frame_rate,
-                    num_channels,
-                    format,
-                    &[],
+                    &StreamParams::new(num_channels, format, frame_rate, buffer_size),
                     ex,
                 )
                 .await?,
@@ -812,13 +3296,9 @@ impl<'a> StreamSource for CrasClient<'a> {
             Ok((
                 Box::new(NoopStreamControl::new()),
                 Box::new(self.create_stream::<CrasCaptureData>(
-                    None,
-                    buffer_size as u32,
                     CRAS_STREAM_DIRECTION::CRAS_STREAM_INPUT,
-                    frame_rate,
-                    num_channels,
-                    format,
-                    effects,
+                    &StreamParams::new(num_channels, format, frame_rate, buffer_size)
+                        .effects(effects),
                 )?),
             ))
         } else {
@@ -849,13 +3329,9 @@ impl<'a> StreamSource for CrasClient<'a> {
             Ok((
                 Box::new(NoopStreamControl::new()),
                 Box::new(self.create_async_stream::<async_::CrasCaptureData>(
-                    None,
-                    buffer_size as u32,
                     CRAS_STREAM_DIRECTION::CRAS_STREAM_INPUT,
-                    frame_rate,
-                    num_channels,
-                    format,
-                    effects,
+                    &StreamParams::new(num_channels, format, frame_rate, buffer_size)
+                        .effects(effects),
                     ex,
                 )?),
             ))
@@ -888,13 +3364,9 @@ impl<'a> StreamSource for CrasClient<'a> {
                 Box::new(NoopStreamControl::new()),
                 Box::new(
                     self.async_create_async_stream::<async_::CrasCaptureData>(
-                        None,
-                        buffer_size as u32,
                         CRAS_STREAM_DIRECTION::CRAS_STREAM_INPUT,
-                        frame_rate,
-                        num_channels,
-                        format,
-                        effects,
+                        &StreamParams::new(num_channels, format, frame_rate, buffer_size)
+                            .effects(effects),
                         ex,
                     )
                     .await?,
@@ -941,36 +3413,28 @@ impl<'a, E: std::error::Error> ShmStreamSource<E> for CrasClient<'a> {
 
         let (sock1, sock2) = UnixStream::pair()?;
         let stream_id = self.prepare_and_send_connect_stream(
-            None,
-            buffer_size as u32,
             direction.into(),
-            frame_rate,
-            num_channels,
-            format,
-            effects,
+            &StreamParams::new(num_channels, format, frame_rate, buffer_size).effects(effects),
             client_shm.size(),
             buffer_offsets,
             &[sock2.as_raw_fd(), client_shm.as_raw_fd()],
         )?;
 
-        loop {
-            let result = CrasClient::wait_for_message(&mut self.server_socket)?;
-            if let ServerResult::StreamConnected(_stream_id, header_fd, _samples_fd) = result {
-                let audio_socket = AudioSocket::new(sock1);
-                let stream = CrasShmStream::try_new(
-                    stream_id,
-                    self.server_socket.try_clone()?,
-                    audio_socket,
-                    direction,
-                    num_channels,
-                    frame_rate,
-                    format,
-                    header_fd,
-                    client_shm.size() as usize,
-                )?;
-                return Ok(Box::new(stream));
-            }
-        }
+        let (header_fd, _samples_fd, _format) = self.wait_for_stream_connected(stream_id, None)?;
+        self.record_stream_shm(stream_id, client_shm.size() as usize);
+        let audio_socket = AudioSocket::new(sock1);
+        let stream = CrasShmStream::try_new(
+            stream_id,
+            self.server_socket.try_clone()?,
+            audio_socket,
+            direction,
+            num_channels,
+            frame_rate,
+            format,
+            header_fd,
+            client_shm.size() as usize,
+        )?;
+        Ok(Box::new(stream))
     }
 
     fn keep_fds(&self) -> Vec<RawFd> {
@@ -0,0 +1,77 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+use audio_streams::SampleFormat;
+
+use crate::{CrasCaptureData, CrasClient, CrasStream, Error, LoopbackSource, Result, StreamParams};
+
+// The format of the loopback stream. A single channel is enough for a level, the
+// server downmixes the output to it.
+const FRAME_RATE: u32 = 48000;
+const BUFFER_SIZE: usize = 480;
+
+/// A meter of the peak level of the audio played by the server.
+///
+/// The server neither reports the level of its output in the server state nor
+/// answers a message for it, so the meter records the output path from a
+/// loopback device with a mono stream of 10 ms buffers. The loopback stream costs
+/// a capture stream on the server, but no effects are applied on it.
+///
+/// # Example
+///
+/// ```
+/// use libcras::{CrasClient, LoopbackSource, PeakMeter};
+///
+/// # fn meter(client: &mut CrasClient) -> Result<(), libcras::Error> {
+/// client.enable_cras_capture();
+/// let mut meter = PeakMeter::new(client, LoopbackSource::PostDsp)?;
+/// loop {
+///     println!("{:.2}", meter.level()?);
+/// }
+/// # }
+/// ```
+pub struct PeakMeter<'b> {
+    stream: CrasStream<'b, CrasCaptureData<'b>>,
+}
+
+impl<'b> PeakMeter<'b> {
+    /// Connects a loopback stream recording the output path at `source`.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::NodeTypeNotFound` - If the server has no loopback device for
+    ///   `source`.
+    /// * If connecting the stream failed, see `CrasClient::new_cras_capture_stream`.
+    ///   Capture must have been enabled by `CrasClient::enable_cras_capture`.
+    pub fn new(client: &mut CrasClient, source: LoopbackSource) -> Result<Self> {
+        let node_type = source.node_type();
+        let node = client
+            .input_nodes()
+            .find(|node| node.node_type == node_type)
+            .ok_or(Error::NodeTypeNotFound(node_type))?;
+        let params = StreamParams::new(1, SampleFormat::S16LE, FRAME_RATE, BUFFER_SIZE)
+            .device_index(node.iodev_index);
+        Ok(Self {
+            stream: client.new_cras_capture_stream(&params)?,
+        })
+    }
+
+    /// Waits for the next 10 ms of output and gets their peak level, from 0.0 for
+    /// silence to 1.0 for full scale.
+    ///
+    /// # Errors
+    ///
+    /// * If reading the loopback stream failed, e.g. when the server removed it.
+    pub fn level(&mut self) -> Result<f32> {
+        let buffer = match self.stream.frames().next() {
+            Some(buffer) => buffer?,
+            None => return Ok(0.0),
+        };
+        let peak = buffer
+            .chunks_exact(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]).unsigned_abs())
+            .max()
+            .unwrap_or(0);
+        Ok(f32::from(peak) / 32768.0)
+    }
+}
@@ -5,8 +5,11 @@
 use std::ffi::CString;
 use std::fmt::Display;
 use std::os::raw::c_char;
+use std::path::PathBuf;
 use std::ptr;
+use std::sync::Mutex;
 use std::time::Duration;
+use std::time::Instant;
 
 use dbus::blocking::Connection;
 use dbus::blocking::Proxy;
@@ -20,7 +23,7 @@ use thiserror::Error;
 const DBUS_TIMEOUT: Duration = Duration::from_millis(500);
 
 #[derive(Error, Debug)]
-enum Error {
+pub enum Error {
     #[error("D-Bus failure: {0:#}")]
     DBus(#[from] dbus::Error),
     #[error("protocol buffers failure: {0:#}")]
@@ -48,6 +51,30 @@ impl CrasDlcId {
     }
 }
 
+/// An audio feature of CRAS backed by a DLC package.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DlcFeature {
+    /// Speech super resolution of narrowband Bluetooth HFP input, backed by
+    /// "sr-bt-dlc".
+    BluetoothSuperResolution,
+    /// The noise cancellation CRAS runs on the AP for capture, backed by
+    /// "nc-ap-dlc". It is not a stream effect clients request: it is the system
+    /// noise cancellation of the nodes which support it, toggled for the whole
+    /// system.
+    NoiseCancellation,
+}
+
+impl CrasDlcId {
+    /// Returns the features CRAS gets from the package once it is installed.
+    pub fn features(&self) -> &'static [DlcFeature] {
+        match self {
+            CrasDlcId::CrasDlcSrBt => &[DlcFeature::BluetoothSuperResolution],
+            CrasDlcId::CrasDlcNcAp => &[DlcFeature::NoiseCancellation],
+            CrasDlcId::NumCrasDlc => &[],
+        }
+    }
+}
+
 impl Display for CrasDlcId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(self.as_str())
@@ -56,6 +83,154 @@ impl Display for CrasDlcId {
 
 type Result<T> = std::result::Result<T, Error>;
 
+// The error code reported by dlcservice when there is no error.
+const DLC_ERROR_NONE: &str = "org.chromium.DlcServiceInterface.NONE";
+
+/// The state of a DLC package.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DlcStatus {
+    /// The package is not installed.
+    NotInstalled,
+    /// The package is being installed, `progress` is within 0.0..=1.0.
+    Installing { progress: f64 },
+    /// The package is installed and mounted at `root`.
+    Installed { root: PathBuf },
+    /// The last installation of the package failed with the dlcservice error
+    /// code `reason`.
+    Failed { reason: String },
+}
+
+impl From<DlcState> for DlcStatus {
+    fn from(dlc_state: DlcState) -> Self {
+        match dlc_state.state {
+            DlcState_State::INSTALLED => DlcStatus::Installed {
+                root: PathBuf::from(dlc_state.root_path),
+            },
+            DlcState_State::INSTALLING => DlcStatus::Installing {
+                progress: dlc_state.progress,
+            },
+            DlcState_State::NOT_INSTALLED => {
+                if dlc_state.last_error_code.is_empty()
+                    || dlc_state.last_error_code == DLC_ERROR_NONE
+                {
+                    DlcStatus::NotInstalled
+                } else {
+                    DlcStatus::Failed {
+                        reason: dlc_state.last_error_code,
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl DlcStatus {
+    /// Returns `true` if the package is installed, so its features are usable.
+    pub fn is_usable(&self) -> bool {
+        matches!(self, DlcStatus::Installed { .. })
+    }
+}
+
+/// The features of a DLC package and whether they are usable.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DlcFeatures {
+    pub features: &'static [DlcFeature],
+    pub usable: bool,
+}
+
+/// Returns the features of the DLC package `id` in the state `status`, e.g. to
+/// offer installing the package while they are not usable.
+pub fn dlc_features(id: CrasDlcId, status: &DlcStatus) -> DlcFeatures {
+    DlcFeatures {
+        features: id.features(),
+        usable: status.is_usable(),
+    }
+}
+
+// The progress of an installation when it was first sampled.
+#[derive(Clone, Copy)]
+struct ProgressSample {
+    time: Instant,
+    progress: f64,
+    last_progress: f64,
+}
+
+/// Estimates the remaining time of DLC installations from their progress over
+/// time.
+///
+/// dlcservice only reports the progress of an installation as a fraction, so the
+/// estimate is the remaining fraction at the average rate since the installation
+/// was first sampled. The estimate is reset when a package is not being installed
+/// or its progress goes backwards, which happens when a new installation starts.
+/// A progress which is not a finite number is ignored.
+pub struct DlcProgressTracker {
+    samples: [Option<ProgressSample>; CrasDlcId::NumCrasDlc as usize],
+}
+
+impl DlcProgressTracker {
+    pub const fn new() -> Self {
+        Self {
+            samples: [None; CrasDlcId::NumCrasDlc as usize],
+        }
+    }
+
+    /// Records the `status` of the DLC package `id` observed at `now`.
+    pub fn sample(&mut self, id: CrasDlcId, status: &DlcStatus, now: Instant) {
+        let Some(slot) = self.samples.get_mut(id as usize) else {
+            return;
+        };
+        match status {
+            DlcStatus::Installing { progress } if !progress.is_finite() => {}
+            DlcStatus::Installing { progress } => match slot {
+                Some(sample) if *progress >= sample.last_progress => {
+                    sample.last_progress = *progress;
+                }
+                _ => {
+                    *slot = Some(ProgressSample {
+                        time: now,
+                        progress: *progress,
+                        last_progress: *progress,
+                    });
+                }
+            },
+            _ => *slot = None,
+        }
+    }
+
+    /// Returns the estimated remaining time of the installation of `id` at `now`,
+    /// or `None` if it is not being installed or has made no progress since it
+    /// was first sampled.
+    pub fn eta(&self, id: CrasDlcId, now: Instant) -> Option<Duration> {
+        let sample = self.samples.get(id as usize)?.as_ref()?;
+        let done = sample.last_progress - sample.progress;
+        if done <= 0.0 {
+            return None;
+        }
+        let elapsed = now.saturating_duration_since(sample.time);
+        let remaining = (1.0 - sample.last_progress).max(0.0);
+        // Fails instead of panicking if the rate of a tiny progress overflows.
+        Duration::try_from_secs_f64(elapsed.as_secs_f64() * remaining / done).ok()
+    }
+
+    /// Queries the state of the DLC package `id` from dlcservice, samples it and
+    /// returns the estimated remaining time of its installation. See `eta`.
+    pub fn get_dlc_eta(&mut self, id: CrasDlcId) -> Result<Option<Duration>> {
+        let status = dlc_state(id)?;
+        let now = Instant::now();
+        self.sample(id, &status, now);
+        Ok(self.eta(id, now))
+    }
+}
+
+impl Default for DlcProgressTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The tracker used by `cras_dlc_get_eta_ms`.
+static PROGRESS_TRACKER: Mutex<DlcProgressTracker> = Mutex::new(DlcProgressTracker::new());
+
 fn get_dlcservice_connection_path(connection: &Connection) -> Proxy<&Connection> {
     connection.with_proxy(
         "org.chromium.DlcService",
@@ -84,6 +259,11 @@ fn get_dlc_state(id: CrasDlcId) -> Result<DlcState> {
     Ok(dlc_state)
 }
 
+/// Returns the state of the DLC package `id` as reported by dlcservice.
+pub fn dlc_state(id: CrasDlcId) -> Result<DlcStatus> {
+    get_dlc_state(id).map(DlcStatus::from)
+}
+
 fn get_dlc_root_path(id: CrasDlcId) -> Result<CString> {
     let dlc_state = get_dlc_state(id)?;
     CString::new(dlc_state.root_path).map_err(|e| e.into())
@@ -162,3 +342,153 @@ pub unsafe extern "C" fn cras_dlc_get_root_path(id: CrasDlcId) -> *const c_char
         Err(_) => ptr::null_mut(),
     }
 }
+
+/// Returns the estimated remaining time of the installation of the DLC package
+/// in milliseconds, or -1 if it is unknown.
+///
+/// The estimate is made from the progress sampled by the previous calls, so it is
+/// unknown on the first call for an installation.
+#[no_mangle]
+pub extern "C" fn cras_dlc_get_eta_ms(id: CrasDlcId) -> i64 {
+    let mut tracker = match PROGRESS_TRACKER.lock() {
+        Ok(tracker) => tracker,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    match tracker.get_dlc_eta(id) {
+        Ok(Some(eta)) => eta.as_millis().try_into().unwrap_or(i64::MAX),
+        Ok(None) => -1,
+        Err(err) => {
+            log::warn!("cras_dlc_get_eta_ms({}) failed: {}", id, err);
+            -1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: CrasDlcId = CrasDlcId::CrasDlcNcAp;
+
+    fn installing(progress: f64) -> DlcStatus {
+        DlcStatus::Installing { progress }
+    }
+
+    fn dlc_state_proto(state: DlcState_State, last_error_code: &str) -> DlcState {
+        let mut dlc_state = DlcState::new();
+        dlc_state.set_state(state);
+        dlc_state.set_last_error_code(last_error_code.to_string());
+        dlc_state
+    }
+
+    #[test]
+    fn dlc_status_installed() {
+        let mut dlc_state = dlc_state_proto(DlcState_State::INSTALLED, DLC_ERROR_NONE);
+        dlc_state.set_root_path("/run/imageloader/nc-ap-dlc/package/root".to_string());
+        assert_eq!(
+            DlcStatus::from(dlc_state),
+            DlcStatus::Installed {
+                root: PathBuf::from("/run/imageloader/nc-ap-dlc/package/root"),
+            }
+        );
+    }
+
+    #[test]
+    fn dlc_status_installing() {
+        let mut dlc_state = dlc_state_proto(DlcState_State::INSTALLING, "");
+        dlc_state.set_progress(0.5);
+        assert_eq!(DlcStatus::from(dlc_state), installing(0.5));
+    }
+
+    #[test]
+    fn dlc_status_not_installed() {
+        let dlc_state = dlc_state_proto(DlcState_State::NOT_INSTALLED, "");
+        assert_eq!(DlcStatus::from(dlc_state), DlcStatus::NotInstalled);
+        let dlc_state = dlc_state_proto(DlcState_State::NOT_INSTALLED, DLC_ERROR_NONE);
+        assert_eq!(DlcStatus::from(dlc_state), DlcStatus::NotInstalled);
+    }
+
+    #[test]
+    fn dlc_status_failed() {
+        let reason = "org.chromium.DlcServiceInterface.INTERNAL";
+        let dlc_state = dlc_state_proto(DlcState_State::NOT_INSTALLED, reason);
+        assert_eq!(
+            DlcStatus::from(dlc_state),
+            DlcStatus::Failed {
+                reason: reason.to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn eta_from_two_samples() {
+        let mut tracker = DlcProgressTracker::new();
+        let start = Instant::now();
+        tracker.sample(ID, &installing(0.25), start);
+        let now = start + Duration::from_secs(2);
+        tracker.sample(ID, &installing(0.5), now);
+        assert_eq!(tracker.eta(ID, now), Some(Duration::from_secs(4)));
+        assert_eq!(tracker.eta(CrasDlcId::CrasDlcSrBt, now), None);
+    }
+
+    #[test]
+    fn eta_reset_when_progress_goes_backwards() {
+        let mut tracker = DlcProgressTracker::new();
+        let start = Instant::now();
+        tracker.sample(ID, &installing(0.25), start);
+        tracker.sample(ID, &installing(0.5), start + Duration::from_secs(2));
+        let restart = start + Duration::from_secs(3);
+        tracker.sample(ID, &installing(0.125), restart);
+        assert_eq!(tracker.eta(ID, restart), None);
+        let now = restart + Duration::from_secs(1);
+        tracker.sample(ID, &installing(0.375), now);
+        assert_eq!(tracker.eta(ID, now), Some(Duration::from_millis(2500)));
+    }
+
+    #[test]
+    fn eta_reset_when_not_installing() {
+        let mut tracker = DlcProgressTracker::new();
+        let start = Instant::now();
+        tracker.sample(ID, &installing(0.25), start);
+        let now = start + Duration::from_secs(2);
+        tracker.sample(ID, &installing(0.5), now);
+        tracker.sample(ID, &DlcStatus::NotInstalled, now);
+        assert_eq!(tracker.eta(ID, now), None);
+        tracker.sample(ID, &installing(0.75), now + Duration::from_secs(1));
+        assert_eq!(tracker.eta(ID, now + Duration::from_secs(1)), None);
+    }
+
+    #[test]
+    fn eta_none_without_progress() {
+        let mut tracker = DlcProgressTracker::new();
+        let start = Instant::now();
+        assert_eq!(tracker.eta(ID, start), None);
+        tracker.sample(ID, &installing(0.25), start);
+        let now = start + Duration::from_secs(2);
+        tracker.sample(ID, &installing(0.25), now);
+        assert_eq!(tracker.eta(ID, now), None);
+    }
+
+    #[test]
+    fn eta_ignores_invalid_progress() {
+        let mut tracker = DlcProgressTracker::new();
+        let start = Instant::now();
+        tracker.sample(ID, &installing(f64::NAN), start);
+        assert_eq!(tracker.eta(ID, start), None);
+        tracker.sample(ID, &installing(0.25), start);
+        let now = start + Duration::from_secs(2);
+        tracker.sample(ID, &installing(f64::NAN), now);
+        tracker.sample(ID, &installing(0.5), now);
+        assert_eq!(tracker.eta(ID, now), Some(Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn eta_none_when_it_overflows() {
+        let mut tracker = DlcProgressTracker::new();
+        let start = Instant::now();
+        tracker.sample(ID, &installing(0.25), start);
+        let now = start + Duration::from_secs(100_000);
+        tracker.sample(ID, &installing(0.25 + f64::EPSILON), now);
+        assert_eq!(tracker.eta(ID, now), None);
+    }
+}
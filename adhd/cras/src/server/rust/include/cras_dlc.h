@@ -66,6 +66,15 @@ bool cras_dlc_is_available(enum CrasDlcId id);
  */
 const char *cras_dlc_get_root_path(enum CrasDlcId id);
 
+/**
+ * Returns the estimated remaining time of the installation of the DLC package
+ * in milliseconds, or -1 if it is unknown.
+ *
+ * The estimate is made from the progress sampled by the previous calls, so it is
+ * unknown on the first call for an installation.
+ */
+int64_t cras_dlc_get_eta_ms(enum CrasDlcId id);
+
 #endif /* CRAS_SRC_SERVER_RUST_INCLUDE_CRAS_DLC_H_ */
 
 #ifdef __cplusplus
@@ -195,6 +195,7 @@ impl PlayZeroWorker {
                 Self::FRAME_RATE,
                 Self::FRAMES_PER_BUFFER,
                 &[],
+                None,
             )
             .map_err(|e| Error::NewPlayStreamFailed(e))?;
 